@@ -0,0 +1,225 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use slog::error;
+use crate::LOG;
+
+/// Who opened a file handle and what it has read so far.
+pub(crate) struct Access {
+  /// Virtual path of the file at the time it was opened.
+  pub(crate) path: String,
+  pub(crate) uid: u32,
+  pub(crate) gid: u32,
+  pub(crate) pid: u32,
+  opened: Instant,
+  bytes_read: AtomicU64,
+}
+
+impl Access {
+  pub(crate) fn new(path: String, uid: u32, gid: u32, pid: u32) -> Self {
+    Access { path, uid, gid, pid, opened: Instant::now(), bytes_read: AtomicU64::new(0) }
+  }
+
+  pub(crate) fn add_bytes_read(&self, bytes: usize) {
+    self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+}
+
+/// Entries waiting for the writer at most. Past that they are dropped rather
+/// than holding up the filesystem.
+const BACKLOG: usize = 4096;
+
+enum Message {
+  Entry(Value),
+  /// Answered once everything sent before is written.
+  Flush(mpsc::Sender<()>),
+}
+
+/// JSON lines, one per open and release, in a file that is rotated to
+/// `<path>.1`, `<path>.2`, ... once it grows past `max_size`. Lines are
+/// written by a thread of their own, FUSE callbacks only queue them.
+pub(crate) struct AccessLog {
+  sender: mpsc::SyncSender<Message>,
+  /// Entries dropped since the writer last caught up.
+  dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+  pub(crate) fn open(path: &str, max_size: u64, keep: usize) -> Result<Self, Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let writer = Writer { path: path.to_string(), max_size, keep, file, size, dropped: dropped.clone() };
+    std::thread::Builder::new()
+      .name(String::from("access-log"))
+      .spawn(move || writer.run(receiver))?;
+    Ok(AccessLog { sender, dropped })
+  }
+
+  pub(crate) fn opened(&self, fh: u64, ino: u64, target: &str, flags: i32, access: &Access) {
+    self.write(json!({
+      "event": "open",
+      "fh": fh,
+      "ino": ino,
+      "path": access.path,
+      "target": target,
+      "flags": flags,
+      "uid": access.uid,
+      "gid": access.gid,
+      "pid": access.pid,
+    }));
+  }
+
+  /// An open that was refused, with the errno it was refused with.
+  pub(crate) fn failed(&self, ino: u64, target: &str, flags: i32, access: &Access, errno: i32) {
+    self.write(json!({
+      "event": "open_failed",
+      "ino": ino,
+      "path": access.path,
+      "target": target,
+      "flags": flags,
+      "uid": access.uid,
+      "gid": access.gid,
+      "pid": access.pid,
+      "errno": errno,
+    }));
+  }
+
+  pub(crate) fn released(&self, fh: u64, ino: u64, target: &str, access: &Access) {
+    self.write(json!({
+      "event": "release",
+      "fh": fh,
+      "ino": ino,
+      "path": access.path,
+      "target": target,
+      "uid": access.uid,
+      "gid": access.gid,
+      "pid": access.pid,
+      "bytes_read": access.bytes_read.load(Ordering::Relaxed),
+      "duration_ms": access.opened.elapsed().as_millis() as u64,
+    }));
+  }
+
+  /// Waits until every entry queued so far is written.
+  pub(crate) fn flush(&self) {
+    let (sender, receiver) = mpsc::channel();
+    if self.sender.send(Message::Flush(sender)).is_ok() {
+      let _ = receiver.recv();
+    }
+  }
+
+  /// Queues `entry` with the time of the event. Failing to log never fails
+  /// the operation, it is reported on the main log instead.
+  fn write(&self, mut entry: Value) {
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    entry["ts_ms"] = json!(ts);
+    if self.sender.try_send(Message::Entry(entry)).is_err() {
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+/// The writing end of an `AccessLog`.
+struct Writer {
+  path: String,
+  max_size: u64,
+  /// Rotated files kept besides the current one.
+  keep: usize,
+  file: File,
+  /// Size of `file`.
+  size: u64,
+  dropped: Arc<AtomicU64>,
+}
+
+impl Writer {
+  fn run(mut self, receiver: mpsc::Receiver<Message>) {
+    for message in receiver {
+      match message {
+        Message::Entry(entry) => self.write(entry),
+        Message::Flush(done) => {
+          let _ = done.send(());
+        }
+      }
+      let dropped = self.dropped.swap(0, Ordering::Relaxed);
+      if dropped > 0 {
+        error!(LOG, "Dropped {} access log entries, writing {} fell behind", dropped, self.path);
+      }
+    }
+  }
+
+  fn write(&mut self, entry: Value) {
+    let mut line = entry.to_string();
+    line.push('\n');
+    if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+      match self.rotate() {
+        Ok(rotated) => (self.file, self.size) = (rotated, 0),
+        Err(err) => error!(LOG, "Failed to rotate access log {}: {}", self.path, err),
+      }
+    }
+    match self.file.write_all(line.as_bytes()) {
+      Ok(()) => self.size += line.len() as u64,
+      Err(err) => error!(LOG, "Failed to write access log {}: {}", self.path, err),
+    }
+  }
+
+  /// Shifts every rotated file one number up, dropping the oldest, and
+  /// starts a new current file.
+  fn rotate(&self) -> Result<File, Error> {
+    if self.keep == 0 {
+      return OpenOptions::new().create(true).write(true).truncate(true).open(&self.path);
+    }
+    for number in (1..self.keep).rev() {
+      let from = format!("{}.{}", self.path, number);
+      if std::path::Path::new(&from).exists() {
+        std::fs::rename(&from, format!("{}.{}", self.path, number + 1))?;
+      }
+    }
+    std::fs::rename(&self.path, format!("{}.1", self.path))?;
+    OpenOptions::new().create(true).append(true).open(&self.path)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_rotate() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let path = dir.join("access.log").to_string_lossy().into_owned();
+
+    let log = AccessLog::open(&path, 600, 2).unwrap();
+    let access = Access::new(String::from("/record1/bundle.zip"), 1000, 100, 42);
+    access.add_bytes_read(4096);
+    log.opened(3, 7, "/blobs/bundle", libc::O_RDONLY, &access);
+    log.released(3, 7, "/blobs/bundle", &access);
+    log.failed(8, "/blobs/missing", libc::O_RDONLY, &access, libc::EIO);
+    log.flush();
+
+    let lines = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["event"], "open");
+    assert_eq!(lines[0]["path"], "/record1/bundle.zip");
+    assert_eq!(lines[1]["event"], "release");
+    assert_eq!(lines[1]["uid"], 1000);
+    assert_eq!(lines[1]["pid"], 42);
+    assert_eq!(lines[1]["bytes_read"], 4096);
+    assert_eq!(lines[2]["event"], "open_failed");
+    assert_eq!(lines[2]["errno"], libc::EIO);
+
+    for _ in 0..6 {
+      log.released(3, 7, "/blobs/bundle", &access);
+    }
+    log.flush();
+    assert!(dir.join("access.log.1").exists());
+    assert!(dir.join("access.log.2").exists());
+    assert!(!dir.join("access.log.3").exists());
+    assert!(std::fs::metadata(&path).unwrap().len() <= 600);
+  }
+}
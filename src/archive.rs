@@ -0,0 +1,918 @@
+use crate::mapping::{Metadata, Path};
+use crate::{read_full_at, LOG};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use lazy_static::lazy_static;
+use miniz_oxide::inflate::core::inflate_flags::{TINFL_FLAG_HAS_MORE_INPUT, TINFL_FLAG_STOP_ON_BLOCK_BOUNDARY};
+use miniz_oxide::inflate::core::{decompress, BlockBoundaryState, DecompressorOxide, TINFL_LZ_DICT_SIZE};
+use miniz_oxide::inflate::TINFLStatus;
+use slog::{error, warn};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Take};
+use std::ops::Range;
+use std::path::Component;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tar::EntryType;
+use zip::{CompressionMethod, ZipArchive};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Uncompressed bytes between two restart points of a gzip index, to begin
+/// with. Each point costs the 32 KiB window, so an index takes about 3% of
+/// the data it covers.
+const GZIP_SPAN: u64 = 1 << 20;
+/// Restart points an index holds at most, 4 MiB of windows. A full index
+/// drops every other point and keeps going at twice the span.
+const GZIP_POINTS: usize = 128;
+/// Gzip blobs an index is kept for, the least recently opened goes first.
+const GZIP_INDEXES: usize = 32;
+/// Compressed bytes read from a gzip blob at a time.
+const GZIP_CHUNK: usize = 64 * 1024;
+
+lazy_static! {
+    /// Indexes by blob, so that opening one member after another carries on
+    /// from what earlier opens inflated instead of starting over every time.
+    static ref INDEXES: Mutex<HashMap<String, SharedIndex>> = Mutex::new(HashMap::new());
+}
+
+/// How the bytes of a member are stored inside its archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Stored,
+    Deflated,
+    /// Member of a tar.gz, `data_start` is an offset in the uncompressed
+    /// stream.
+    Gzip,
+}
+
+/// Where one member lives inside the blob holding its archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveMember {
+    pub data_start: u64,
+    pub compressed_size: u64,
+    pub size: u64,
+    pub compression: Compression,
+}
+
+/// Replaces every `Path::Zip` and `Path::Tar` in `mapping` by a folder of the
+/// archive's members. Only the listing is read, member data stays in the blob
+/// until it is opened. A broken archive shows up as an empty folder.
+pub fn expand(mapping: &mut Path) {
+    match mapping {
+        Path::File { .. } | Path::Symlink { .. } | Path::Blob { .. } => {}
+        Path::Folder { paths, .. } => {
+            for path in paths.values_mut() {
+                expand(path);
+            }
+        }
+        Path::Zip { name, path } => {
+            let paths = list_zip(path).unwrap_or_else(|err| {
+                error!(LOG, "Failed to read zip archive {}: {}", path, err);
+                HashMap::new()
+            });
+            *mapping = Path::Folder {
+                name: name.clone(),
+                paths,
+                meta: Metadata::default(),
+            };
+        }
+        Path::Tar { name, path } => {
+            let paths = list_tar(path).unwrap_or_else(|err| {
+                error!(LOG, "Failed to read tar archive {}: {}", path, err);
+                HashMap::new()
+            });
+            *mapping = Path::Folder {
+                name: name.clone(),
+                paths,
+                meta: Metadata::default(),
+            };
+        }
+    }
+}
+
+/// Names along `path`, or `None` when it would climb out of the archive.
+fn safe_components(path: &std::path::Path) -> Option<Vec<String>> {
+    let mut components = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str()?.to_string()),
+            Component::ParentDir => return None,
+            _ => {}
+        }
+    }
+    Some(components)
+}
+
+fn list_zip(path: &str) -> Result<HashMap<String, Path>, Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut root = HashMap::new();
+    for idx in 0..archive.len() {
+        let file = archive.by_index_raw(idx)?;
+        let Some(mut components) = file.enclosed_name().and_then(safe_components) else {
+            warn!(LOG, "Skipping unsafe member {} of {}", file.name(), path);
+            continue;
+        };
+        if file.is_dir() {
+            folder_at(&mut root, &components);
+            continue;
+        }
+        let Some(name) = components.pop() else {
+            continue;
+        };
+
+        // A link stores its target as the member data
+        if file.unix_mode().is_some_and(|mode| mode & libc::S_IFMT == libc::S_IFLNK) {
+            drop(file);
+            let mut target = String::new();
+            if let Err(err) = archive.by_index(idx)?.read_to_string(&mut target) {
+                warn!(LOG, "Skipping symlink {} of {}: {}", name, path, err);
+                continue;
+            }
+            folder_at(&mut root, &components).insert(name.clone(), Path::Symlink { name, target });
+            continue;
+        }
+
+        let compression = match file.compression() {
+            CompressionMethod::Stored => Compression::Stored,
+            CompressionMethod::Deflated => Compression::Deflated,
+            method => {
+                warn!(LOG, "Skipping member {} of {}: unsupported compression {}", file.name(), path, method);
+                continue;
+            }
+        };
+        let member = ArchiveMember {
+            data_start: file.data_start(),
+            compressed_size: file.compressed_size(),
+            size: file.size(),
+            compression,
+        };
+        folder_at(&mut root, &components).insert(
+            name.clone(),
+            Path::File {
+                name,
+                path: path.to_string(),
+                member: Some(member),
+                sha256: None,
+                meta: Metadata {
+                    mode: file.unix_mode().map(|mode| mode & 0o7777),
+                    ..Metadata::default()
+                },
+            },
+        );
+    }
+    Ok(root)
+}
+
+/// Tar blobs are told apart from tar.gz ones by the gzip magic, whatever the
+/// name says.
+fn list_tar(path: &str) -> Result<HashMap<String, Path>, Error> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 2];
+    let gzip = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    file.rewind()?;
+    if gzip {
+        list_tar_entries(path, MultiGzDecoder::new(file), Compression::Gzip)
+    } else {
+        list_tar_entries(path, file, Compression::Stored)
+    }
+}
+
+fn list_tar_entries<R: Read>(path: &str, reader: R, compression: Compression) -> Result<HashMap<String, Path>, Error> {
+    let mut archive = tar::Archive::new(reader);
+    let mut root = HashMap::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let Some(mut components) = safe_components(&entry_path) else {
+            warn!(LOG, "Skipping unsafe member {} of {}", entry_path.display(), path);
+            continue;
+        };
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                folder_at(&mut root, &components);
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let Some(name) = components.pop() else {
+                    continue;
+                };
+                // The owner is whoever built the archive, only mode and
+                // mtime carry over
+                let meta = Metadata {
+                    mode: entry.header().mode().ok().map(|mode| mode & 0o7777),
+                    mtime: entry.header().mtime().ok().map(|mtime| mtime as i64),
+                    ..Metadata::default()
+                };
+                let member = ArchiveMember {
+                    data_start: entry.raw_file_position(),
+                    compressed_size: entry.size(),
+                    size: entry.size(),
+                    compression,
+                };
+                folder_at(&mut root, &components).insert(
+                    name.clone(),
+                    Path::File {
+                        name,
+                        path: path.to_string(),
+                        member: Some(member),
+                        sha256: None,
+                        meta,
+                    },
+                );
+            }
+            EntryType::Symlink => {
+                let (Some(name), Some(target)) = (components.pop(), entry.link_name()?) else {
+                    continue;
+                };
+                let target = target.to_string_lossy().into_owned();
+                folder_at(&mut root, &components).insert(name.clone(), Path::Symlink { name, target });
+            }
+            kind => {
+                warn!(LOG, "Skipping member {} of {}: unsupported entry type {:?}", entry_path.display(), path, kind);
+            }
+        }
+    }
+    Ok(root)
+}
+
+/// Entries of the folder at `components` below `paths`, creating the folders
+/// on the way.
+fn folder_at<'a>(paths: &'a mut HashMap<String, Path>, components: &[String]) -> &'a mut HashMap<String, Path> {
+    let Some((first, rest)) = components.split_first() else {
+        return paths;
+    };
+    let entry = paths.entry(first.clone()).or_insert_with(|| Path::Folder {
+        name: first.clone(),
+        paths: HashMap::new(),
+        meta: Metadata::default(),
+    });
+    if !matches!(entry, Path::Folder { .. }) {
+        *entry = Path::Folder {
+            name: first.clone(),
+            paths: HashMap::new(),
+            meta: Metadata::default(),
+        };
+    }
+    let Path::Folder { paths, .. } = entry else {
+        unreachable!("entry was just made a folder");
+    };
+    folder_at(paths, rest)
+}
+
+/// Raw, still compressed, bytes of `member`. Not meant for gzip members,
+/// which have no offset of their own in the blob.
+fn member_data(mut archive: File, member: &ArchiveMember) -> Result<Take<File>, Error> {
+    archive.seek(SeekFrom::Start(member.data_start))?;
+    Ok(archive.take(member.compressed_size))
+}
+
+/// Where inflating a gzip blob can start over without going back to the top,
+/// like in zlib's zran example: the boundary between two deflate blocks, the
+/// bits of the last byte read that belong to the next block, and the 32 KiB
+/// of output the next blocks may refer back to.
+struct RestartPoint {
+    /// Offset in the uncompressed stream.
+    position: u64,
+    /// Offset in the blob of the first byte not yet read.
+    input: u64,
+    state: BlockBoundaryState,
+    /// Oldest byte first.
+    window: Vec<u8>,
+}
+
+/// Inflates a gzip blob member after member, like `MultiGzDecoder`, from the
+/// top or from a restart point. Reads go through the file it is given, it
+/// holds no file handle of its own.
+struct Inflater {
+    decompressor: Box<DecompressorOxide>,
+    /// Wrapping output buffer, which is the window at the same time.
+    window: Vec<u8>,
+    /// Where the next output goes in `window`.
+    window_pos: usize,
+    /// Offset in the uncompressed stream of the next output.
+    position: u64,
+    /// Compressed bytes read ahead, starting at `input_offset` in the blob.
+    input: Vec<u8>,
+    input_pos: usize,
+    input_offset: u64,
+    /// Inside the deflate stream of a member, unset between members.
+    in_member: bool,
+    done: bool,
+}
+
+impl Inflater {
+    fn start() -> Self {
+        Self {
+            decompressor: Box::default(),
+            window: vec![0; TINFL_LZ_DICT_SIZE],
+            window_pos: 0,
+            position: 0,
+            input: vec![],
+            input_pos: 0,
+            input_offset: 0,
+            in_member: false,
+            done: false,
+        }
+    }
+
+    /// Picks up at `point`. The window goes in whole with its newest byte
+    /// last, so a distance of `n` from the first output reaches `n` bytes back.
+    fn resume(point: &RestartPoint) -> Self {
+        Self {
+            decompressor: Box::new(DecompressorOxide::from_block_boundary_state(&point.state)),
+            window: point.window.clone(),
+            position: point.position,
+            input_offset: point.input,
+            in_member: true,
+            ..Self::start()
+        }
+    }
+
+    /// Offset in the blob of the first byte not yet consumed.
+    fn consumed(&self) -> u64 {
+        self.input_offset + self.input_pos as u64
+    }
+
+    /// Drops what was read ahead and carries on at `offset` in the blob.
+    fn skip_to(&mut self, offset: u64) {
+        self.input.clear();
+        self.input_pos = 0;
+        self.input_offset = offset;
+    }
+
+    /// Reads the next chunk once everything read so far was consumed. False
+    /// at the end of the blob.
+    fn fill(&mut self, file: &File) -> Result<bool, Error> {
+        if self.input_pos < self.input.len() {
+            return Ok(true);
+        }
+        let offset = self.consumed();
+        self.input = read_full_at(file, offset, GZIP_CHUNK)?;
+        self.input_pos = 0;
+        self.input_offset = offset;
+        Ok(!self.input.is_empty())
+    }
+
+    fn restart_point(&self) -> Option<RestartPoint> {
+        let state = self.decompressor.block_boundary_state()?;
+        let mut window = self.window[self.window_pos..].to_vec();
+        window.extend_from_slice(&self.window[..self.window_pos]);
+        Some(RestartPoint {
+            position: self.position,
+            input: self.consumed(),
+            state,
+            window,
+        })
+    }
+
+    /// Inflates the next piece of output and returns its offset in the
+    /// uncompressed stream along with where it is in `window`. `None` at the
+    /// end of the stream, which comes early for a truncated blob. Adds
+    /// restart points to `points` where it has none yet.
+    fn step(&mut self, file: &File, points: &Mutex<GzipPoints>) -> Result<Option<(u64, Range<usize>)>, Error> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            if !self.in_member {
+                match gzip_header_len(file, self.consumed())? {
+                    Some(len) => {
+                        let offset = self.consumed() + len;
+                        self.skip_to(offset);
+                        self.decompressor.init();
+                        self.in_member = true;
+                    }
+                    None => {
+                        self.done = true;
+                        continue;
+                    }
+                }
+            }
+
+            let more = self.fill(file)?;
+            let flags = TINFL_FLAG_STOP_ON_BLOCK_BOUNDARY | if more { TINFL_FLAG_HAS_MORE_INPUT } else { 0 };
+            let (status, consumed, written) = decompress(
+                &mut self.decompressor,
+                &self.input[self.input_pos..],
+                &mut self.window,
+                self.window_pos,
+                flags,
+            );
+            self.input_pos += consumed;
+            let produced = (self.position, self.window_pos..self.window_pos + written);
+            self.position += written as u64;
+            self.window_pos = (self.window_pos + written) % TINFL_LZ_DICT_SIZE;
+
+            match status {
+                TINFLStatus::BlockBoundary => {
+                    let mut points = points.lock().unwrap();
+                    if let Some(idx) = points.wants(self.position) {
+                        if let Some(point) = self.restart_point() {
+                            points.insert(idx, point);
+                        }
+                    }
+                }
+                TINFLStatus::Done => {
+                    // The member ends with its CRC32 and size
+                    let offset = self.consumed() + 8;
+                    self.skip_to(offset);
+                    self.in_member = false;
+                }
+                TINFLStatus::NeedsMoreInput | TINFLStatus::HasMoreOutput => {}
+                TINFLStatus::FailedCannotMakeProgress => self.done = true,
+                status => {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("corrupt gzip stream: {:?}", status)));
+                }
+            }
+            if written > 0 {
+                return Ok(Some(produced));
+            }
+        }
+    }
+}
+
+/// Length of the gzip member header at `offset`, or `None` when no member
+/// starts there.
+fn gzip_header_len(file: &File, offset: u64) -> Result<Option<u64>, Error> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let header = read_full_at(file, offset, 10)?;
+    if header.len() < 10 || header[..2] != GZIP_MAGIC {
+        return Ok(None);
+    }
+    let flags = header[3];
+    let mut len = 10;
+    if flags & FEXTRA != 0 {
+        let extra = read_full_at(file, offset + len, 2)?;
+        if extra.len() < 2 {
+            return Ok(None);
+        }
+        len += 2 + u64::from(u16::from_le_bytes([extra[0], extra[1]]));
+    }
+    for field in [FNAME, FCOMMENT] {
+        if flags & field == 0 {
+            continue;
+        }
+        // Zero terminated
+        loop {
+            let chunk = read_full_at(file, offset + len, 256)?;
+            if chunk.is_empty() {
+                return Ok(None);
+            }
+            match chunk.iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    len += end as u64 + 1;
+                    break;
+                }
+                None => len += chunk.len() as u64,
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+    Ok(Some(len))
+}
+
+/// Restart points of one gzip blob, added as reads go along and shared by
+/// every reader of the blob.
+struct GzipPoints {
+    /// Uncompressed bytes between two points.
+    span: u64,
+    /// Sorted by position.
+    points: Vec<Arc<RestartPoint>>,
+}
+
+impl GzipPoints {
+    fn new() -> Self {
+        Self {
+            span: GZIP_SPAN,
+            points: vec![],
+        }
+    }
+
+    /// Closest point at or before `offset`.
+    fn closest(&self, offset: u64) -> Option<Arc<RestartPoint>> {
+        let idx = self.points.partition_point(|point| point.position <= offset);
+        idx.checked_sub(1).map(|idx| self.points[idx].clone())
+    }
+
+    /// Where a point at `position` goes, when it is a span past the point
+    /// before it, or the top of the blob, and not already known.
+    fn wants(&self, position: u64) -> Option<usize> {
+        let idx = self.points.partition_point(|point| point.position < position);
+        let before = idx.checked_sub(1).map_or(0, |idx| self.points[idx].position);
+        let known = self.points.get(idx).is_some_and(|point| point.position == position);
+        (position >= before + self.span && !known).then_some(idx)
+    }
+
+    fn insert(&mut self, idx: usize, point: RestartPoint) {
+        self.points.insert(idx, Arc::new(point));
+        if self.points.len() > GZIP_POINTS {
+            let mut keep = false;
+            self.points.retain(|_| {
+                keep = !keep;
+                keep
+            });
+            self.span *= 2;
+        }
+    }
+}
+
+/// The index of a blob along with what tells a blob rewritten in place.
+struct SharedIndex {
+    stamp: (u64, SystemTime),
+    used: Instant,
+    points: Arc<Mutex<GzipPoints>>,
+}
+
+/// The index of the blob at `target`, open as `file`, shared with its other
+/// readers. One of its own when the blob cannot be stat'ed.
+fn gzip_points(target: &str, file: &File) -> Arc<Mutex<GzipPoints>> {
+    let Ok(stamp) = file.metadata().and_then(|meta| Ok((meta.len(), meta.modified()?))) else {
+        return Arc::new(Mutex::new(GzipPoints::new()));
+    };
+    let mut indexes = INDEXES.lock().unwrap();
+    if let Some(index) = indexes.get_mut(target).filter(|index| index.stamp == stamp) {
+        index.used = Instant::now();
+        return index.points.clone();
+    }
+    if indexes.len() >= GZIP_INDEXES && !indexes.contains_key(target) {
+        let oldest = indexes.iter().min_by_key(|(_, index)| index.used).map(|(target, _)| target.clone());
+        if let Some(oldest) = oldest {
+            indexes.remove(&oldest);
+        }
+    }
+    let points = Arc::new(Mutex::new(GzipPoints::new()));
+    indexes.insert(
+        target.to_string(),
+        SharedIndex {
+            stamp,
+            used: Instant::now(),
+            points: points.clone(),
+        },
+    );
+    points
+}
+
+/// Reads the uncompressed stream of a gzip blob. Gzip cannot be seeked, so a
+/// read inflates from the closest restart point before its offset, or from
+/// the top of the blob when there is none yet.
+struct GzipIndex {
+    points: Arc<Mutex<GzipPoints>>,
+    /// Left where the last read ended, so sequential reads carry on from it.
+    inflater: Option<Inflater>,
+}
+
+impl GzipIndex {
+    /// Reads up to `size` bytes at `offset` in the uncompressed stream of
+    /// `file`.
+    fn read_at(&mut self, file: &File, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let closest = self.points.lock().unwrap().closest(offset);
+        let floor = closest.as_ref().map_or(0, |point| point.position);
+        let mut inflater = match (self.inflater.take(), closest) {
+            (Some(inflater), _) if inflater.position <= offset && inflater.position >= floor => inflater,
+            (_, Some(point)) => Inflater::resume(&point),
+            (_, None) => Inflater::start(),
+        };
+
+        let end = offset + size as u64;
+        let mut buf = Vec::with_capacity(size);
+        while inflater.position < end {
+            let Some((position, range)) = inflater.step(file, &self.points)? else {
+                break;
+            };
+            let chunk = &inflater.window[range];
+            let from = offset.saturating_sub(position).min(chunk.len() as u64) as usize;
+            let to = (end - position).min(chunk.len() as u64) as usize;
+            buf.extend_from_slice(&chunk[from..to]);
+        }
+        self.inflater = Some(inflater);
+        Ok(buf)
+    }
+}
+
+/// Moves `decoder` from `position` to `offset` and reads up to `size` bytes
+/// there. Returns them along with the position the decoder ended at, which
+/// falls short of `offset + size` when the stream ends early.
+fn read_decoded<R: Read>(decoder: &mut R, position: u64, offset: u64, size: usize) -> Result<(u64, Vec<u8>), Error> {
+    let skipped = std::io::copy(&mut decoder.take(offset - position), &mut std::io::sink())?;
+    let mut buf = Vec::with_capacity(size);
+    decoder.take(size as u64).read_to_end(&mut buf)?;
+    Ok((position + skipped + buf.len() as u64, buf))
+}
+
+/// Writes the uncompressed content of `member` to `dest`.
+pub fn extract(target: &str, member: &ArchiveMember, dest: &std::path::Path) -> Result<(), Error> {
+    let mut out = File::create(dest)?;
+    if member.compression == Compression::Gzip {
+        let mut decoder = MultiGzDecoder::new(File::open(target)?);
+        std::io::copy(&mut (&mut decoder).take(member.data_start), &mut std::io::sink())?;
+        std::io::copy(&mut decoder.take(member.size), &mut out)?;
+        return Ok(());
+    }
+    let mut data = member_data(File::open(target)?, member)?;
+    match member.compression {
+        Compression::Stored => std::io::copy(&mut data, &mut out)?,
+        Compression::Deflated => std::io::copy(&mut DeflateDecoder::new(data), &mut out)?,
+        Compression::Gzip => unreachable!("gzip members are extracted above"),
+    };
+    Ok(())
+}
+
+/// Reads one member of an archive. Stored members are read in place, deflated
+/// ones through a decoder that carries on across forward reads and only
+/// restarts from the top of the member when a read goes backwards. Gzip
+/// members go through an index of the blob shared with every other reader.
+pub struct MemberReader {
+    archive: File,
+    member: ArchiveMember,
+    decoder: Option<(u64, DeflateDecoder<Take<File>>)>,
+    gzip: Option<GzipIndex>,
+}
+
+impl MemberReader {
+    /// Reads `member` out of `archive`, the blob at `target` opened.
+    pub fn new(archive: File, target: &str, member: ArchiveMember) -> Self {
+        let gzip = (member.compression == Compression::Gzip).then(|| GzipIndex {
+            points: gzip_points(target, &archive),
+            inflater: None,
+        });
+        Self {
+            archive,
+            member,
+            decoder: None,
+            gzip,
+        }
+    }
+
+    /// Reads up to `size` bytes at `offset` in the member. Like a file, a
+    /// member cut short by a truncated archive gives a short read.
+    pub fn read_at(&mut self, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let len = min(size as u64, self.member.size.saturating_sub(offset)) as usize;
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        match self.member.compression {
+            Compression::Stored => read_full_at(&self.archive, self.member.data_start + offset, len),
+            Compression::Deflated => {
+                let (position, mut decoder) = match self.decoder.take() {
+                    Some((position, decoder)) if position <= offset => (position, decoder),
+                    _ => (
+                        0,
+                        DeflateDecoder::new(member_data(self.archive.try_clone()?, &self.member)?),
+                    ),
+                };
+                let (position, buf) = read_decoded(&mut decoder, position, offset, len)?;
+                self.decoder = Some((position, decoder));
+                Ok(buf)
+            }
+            Compression::Gzip => {
+                let gzip = self.gzip.as_mut().expect("gzip members get an index");
+                gzip.read_at(&self.archive, self.member.data_start + offset, len)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    fn fake_zip(path: &std::path::Path) -> Vec<u8> {
+        let content: Vec<u8> = (0..100_000u32).flat_map(|n| n.to_le_bytes()).collect();
+        let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+        writer
+            .start_file("stored.txt", FileOptions::default().compression_method(CompressionMethod::Stored))
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.add_directory("dir/empty/", FileOptions::default()).unwrap();
+        writer.start_file("dir/deflated.bin", FileOptions::default()).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.add_symlink("dir/latest", "deflated.bin", FileOptions::default()).unwrap();
+        writer.start_file("../escape.txt", FileOptions::default()).unwrap();
+        writer.finish().unwrap();
+        content
+    }
+
+    #[test]
+    fn test_zip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bundle.zip");
+        let content = fake_zip(&path);
+        let target = path.to_string_lossy().into_owned();
+
+        let mut mapping = Path::Zip {
+            name: String::from("bundle.zip"),
+            path: target.clone(),
+        };
+        expand(&mut mapping);
+        let Path::Folder { paths, .. } = &mapping else {
+            panic!("zip should expand to a folder");
+        };
+        assert_eq!(paths.len(), 2);
+        let Some(Path::File { member: Some(stored), .. }) = paths.get("stored.txt") else {
+            panic!("stored.txt should be an archive member");
+        };
+        let Some(Path::Folder { paths: dir, .. }) = paths.get("dir") else {
+            panic!("dir should be a folder");
+        };
+        assert!(matches!(dir.get("empty"), Some(Path::Folder { .. })));
+        assert!(matches!(dir.get("latest"), Some(Path::Symlink { target, .. }) if target == "deflated.bin"));
+        let Some(Path::File { member: Some(deflated), .. }) = dir.get("deflated.bin") else {
+            panic!("dir/deflated.bin should be an archive member");
+        };
+        assert_eq!(deflated.compression, Compression::Deflated);
+
+        let mut reader = MemberReader::new(File::open(&target).unwrap(), &target, stored.clone());
+        assert_eq!(reader.read_at(1, 10).unwrap(), b"ello");
+        assert!(reader.read_at(10, 10).unwrap().is_empty());
+
+        let mut reader = MemberReader::new(File::open(&target).unwrap(), &target, deflated.clone());
+        assert_eq!(reader.read_at(4096, 4096).unwrap(), &content[4096..8192]);
+        assert_eq!(reader.read_at(8192, 4096).unwrap(), &content[8192..12288]);
+        assert_eq!(reader.read_at(0, 16).unwrap(), &content[..16]);
+        assert_eq!(reader.read_at(399_990, 100).unwrap(), &content[399_990..]);
+
+        let dest = tmp.path().join("deflated.bin");
+        extract(&target, deflated, &dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), content);
+    }
+
+    fn fake_tar<W: Write>(writer: W, content: &[u8]) -> W {
+        let mut builder = tar::Builder::new(writer);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, "./dir/empty/", std::io::empty()).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_600_000_000);
+        builder.append_data(&mut header, "./dir/big.bin", content).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "./dir/latest", "big.bin").unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "hello.txt", &b"hello"[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_tar() {
+        let content: Vec<u8> = (0..100_000u32).flat_map(|n| n.to_le_bytes()).collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let tar_path = tmp.path().join("bundle.tar");
+        let gz_path = tmp.path().join("bundle.tar.gz");
+        fake_tar(File::create(&tar_path).unwrap(), &content);
+        let encoder = flate2::write::GzEncoder::new(File::create(&gz_path).unwrap(), flate2::Compression::default());
+        fake_tar(encoder, &content).finish().unwrap();
+
+        for (path, compression) in [(&tar_path, Compression::Stored), (&gz_path, Compression::Gzip)] {
+            let target = path.to_string_lossy().into_owned();
+            let mut mapping = Path::Tar {
+                name: String::from("bundle.tar"),
+                path: target.clone(),
+            };
+            expand(&mut mapping);
+            let Path::Folder { paths, .. } = &mapping else {
+                panic!("tar should expand to a folder");
+            };
+            assert_eq!(paths.len(), 2);
+            let Some(Path::File { member: Some(hello), .. }) = paths.get("hello.txt") else {
+                panic!("hello.txt should be an archive member");
+            };
+            let Some(Path::Folder { paths: dir, .. }) = paths.get("dir") else {
+                panic!("dir should be a folder");
+            };
+            assert!(matches!(dir.get("empty"), Some(Path::Folder { .. })));
+            assert!(matches!(dir.get("latest"), Some(Path::Symlink { target, .. }) if target == "big.bin"));
+            let Some(Path::File {
+                member: Some(big),
+                meta: big_meta,
+                ..
+            }) = dir.get("big.bin")
+            else {
+                panic!("dir/big.bin should be an archive member");
+            };
+            assert_eq!(big.compression, compression);
+            assert_eq!(big_meta.mode, Some(0o644));
+            assert_eq!(big_meta.mtime, Some(1_600_000_000));
+
+            let mut reader = MemberReader::new(File::open(&target).unwrap(), &target, hello.clone());
+            assert_eq!(reader.read_at(1, 10).unwrap(), b"ello");
+            let mut reader = MemberReader::new(File::open(&target).unwrap(), &target, big.clone());
+            assert_eq!(reader.read_at(200_000, 4096).unwrap(), &content[200_000..204_096]);
+            assert_eq!(reader.read_at(4096, 4096).unwrap(), &content[4096..8192]);
+            assert_eq!(reader.read_at(204_096, 4096).unwrap(), &content[204_096..208_192]);
+            assert_eq!(reader.read_at(399_990, 100).unwrap(), &content[399_990..]);
+
+            let dest = tmp.path().join("big.bin");
+            extract(&target, big, &dest).unwrap();
+            assert_eq!(std::fs::read(&dest).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_gzip_index() {
+        // Two members, like `cat a.gz b.gz`, the second one with a file name
+        let content: Vec<u8> = (0..1_000_000u32).flat_map(|n| (n / 3).to_le_bytes()).collect();
+        let (first, second) = content.split_at(2_500_000);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("two-members.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        encoder.write_all(first).unwrap();
+        let file = encoder.finish().unwrap();
+        let mut encoder = flate2::GzBuilder::new()
+            .filename("second.bin")
+            .write(file, flate2::Compression::default());
+        encoder.write_all(second).unwrap();
+        encoder.finish().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let points = Arc::new(Mutex::new(GzipPoints::new()));
+        let mut index = GzipIndex {
+            points: points.clone(),
+            inflater: None,
+        };
+        assert_eq!(index.read_at(&file, 3_900_000, 4096).unwrap(), &content[3_900_000..3_904_096]);
+        let recorded = points.lock().unwrap().points.len();
+        assert!(recorded >= 3);
+        assert_eq!(index.read_at(&file, 100, 4096).unwrap(), &content[100..4196]);
+        assert_eq!(index.read_at(&file, 2_400_000, 200_000).unwrap(), &content[2_400_000..2_600_000]);
+        assert_eq!(index.read_at(&file, 1_500_000, 4096).unwrap(), &content[1_500_000..1_504_096]);
+        assert_eq!(index.read_at(&file, 3_999_990, 4096).unwrap(), &content[3_999_990..]);
+        assert!(index.read_at(&file, 4_000_000, 4096).unwrap().is_empty());
+        assert_eq!(points.lock().unwrap().points.len(), recorded);
+
+        // Resuming from a point goes no further back than the point itself
+        let point = points.lock().unwrap().points[1].clone();
+        let mut inflater = Inflater::resume(&point);
+        let (position, range) = inflater.step(&file, &Mutex::new(GzipPoints::new())).unwrap().unwrap();
+        assert_eq!(position, point.position);
+        let start = position as usize;
+        assert_eq!(&inflater.window[range.clone()], &content[start..start + range.len()]);
+
+        // A full index thins itself out
+        let mut thinned = GzipPoints::new();
+        for n in 0..=GZIP_POINTS as u64 {
+            let idx = thinned.wants((n + 1) * GZIP_SPAN).unwrap();
+            let position = (n + 1) * GZIP_SPAN;
+            thinned.insert(
+                idx,
+                RestartPoint {
+                    position,
+                    input: point.input,
+                    state: point.state.clone(),
+                    window: vec![],
+                },
+            );
+        }
+        assert_eq!(thinned.points.len(), GZIP_POINTS / 2 + 1);
+        assert_eq!(thinned.span, 2 * GZIP_SPAN);
+        assert!(thinned.wants((GZIP_POINTS as u64 + 2) * GZIP_SPAN).is_none());
+    }
+
+    #[test]
+    fn test_shared_gzip_index() {
+        let content: Vec<u8> = (0..1_000_000u32).flat_map(|n| (n / 5).to_le_bytes()).collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("blob.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        encoder.write_all(&content).unwrap();
+        encoder.finish().unwrap();
+        let target = path.to_string_lossy().into_owned();
+        let member = ArchiveMember {
+            data_start: 0,
+            compressed_size: 0,
+            size: content.len() as u64,
+            compression: Compression::Gzip,
+        };
+        let mut first = MemberReader::new(File::open(&path).unwrap(), &target, member.clone());
+        assert_eq!(first.read_at(3_900_000, 4096).unwrap(), &content[3_900_000..3_904_096]);
+
+        // The second reader goes through a copy with a broken header, so it
+        // can only read where a point of the first one lets it start
+        let copy = tmp.path().join("copy.gz");
+        let mut broken = std::fs::read(&path).unwrap();
+        broken[..10].fill(0);
+        std::fs::write(&copy, broken).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        crate::set_times(&copy.to_string_lossy(), None, Some(fuser::TimeOrNow::SpecificTime(modified))).unwrap();
+        let mut second = MemberReader::new(File::open(&copy).unwrap(), &target, member);
+        let (first_points, second_points) = (&first.gzip.as_ref().unwrap().points, &second.gzip.as_ref().unwrap().points);
+        assert!(Arc::ptr_eq(first_points, second_points));
+        assert_eq!(second.read_at(3_800_000, 4096).unwrap(), &content[3_800_000..3_804_096]);
+        assert!(second.read_at(100, 4096).unwrap().is_empty());
+    }
+}
@@ -1,4 +1,7 @@
 use clap::{crate_version, Parser};
+use std::net::SocketAddr;
+use crate::logging::{LogFilter, LogFormat};
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 #[clap(name = "fs-proxy")]
@@ -12,8 +15,106 @@ pub(crate) struct Args {
   pub auto_unmount: bool,
   #[clap(long = "allow-root", action, help = "Allow root user to access filesystem")]
   pub allow_root: bool,
-  #[clap(long = "mapping-file", help = "Mapping file, a json file that maps file path to destination. e.g. {\"/tmp/hello.txt\": {\"type\": \"File\", \"path\": \"/tmp/hello.txt\"}, \"/tmp/hello\": {\"type\": \"Folder\"}}")]
-  pub mapping_file: String,
+  #[clap(long = "mapping-file", required_unless_present = "mapping_dir", conflicts_with = "mapping_dir", help = "Mapping file, a json file that maps file path to destination. e.g. {\"/tmp/hello.txt\": {\"type\": \"File\", \"path\": \"/tmp/hello.txt\"}, \"/tmp/hello\": {\"type\": \"Folder\"}}")]
+  pub mapping_file: Option<String>,
+  #[clap(long = "mapping-dir", help = "Directory of mapping files, each mounted under a subdirectory named after the file, e.g. <record-id>.json is served at <mountpoint>/<record-id>. Files added or removed are attached or detached on reload")]
+  pub mapping_dir: Option<String>,
+  #[clap(long = "watch-interval", value_parser = clap::value_parser!(u64).range(1..), help = "Poll the mapping file every N seconds and reload it when it changes. SIGHUP always triggers a reload")]
+  pub watch_interval: Option<u64>,
+  #[clap(long = "control-socket", help = "Unix socket accepting JSON-lines commands: status, handles, reload, attach, detach and unmount")]
+  pub control_socket: Option<String>,
+  #[clap(long = "metrics-address", help = "Serve Prometheus metrics of the FUSE operations at http://<address>/metrics, e.g. 127.0.0.1:9100")]
+  pub metrics_address: Option<SocketAddr>,
+  #[clap(long = "access-log", help = "Append one JSON line per open and release to this file: virtual path, blob, uid, gid and pid of the caller, bytes read and duration")]
+  pub access_log: Option<String>,
+  #[clap(long = "access-log-max-size", default_value = "64", value_parser = clap::value_parser!(u64).range(1..=u64::MAX >> 20), help = "Size in MiB at which the access log is rotated to <access-log>.1")]
+  pub access_log_max_size: u64,
+  #[clap(long = "access-log-keep", default_value = "5", help = "Rotated access logs kept besides the current one")]
+  pub access_log_keep: usize,
+  #[clap(long = "upper-dir", help = "Writable upper layer. Mounts read-write, and writes, creates, unlinks and renames land in this directory while the blobs stay untouched")]
+  pub upper_dir: Option<String>,
+  #[clap(long = "blob-root", help = "Content-addressed blob store. {\"type\": \"Blob\", \"sha256\": \"...\"} entries in the mapping are served from <blob-root>/<sha256>")]
+  pub blob_root: Option<String>,
+  #[clap(long = "verify", action, help = "Hash blobs on open and fail with EIO when the content does not match the sha256 in the mapping or in the blob's file name")]
+  pub verify: bool,
+  #[clap(long = "verify-ttl", default_value = "300", value_parser = parse_secs, help = "Seconds a blob found intact is trusted by --verify while its size and mtime stay the same, before an open hashes it again. 0 hashes it on every open")]
+  pub verify_ttl: Duration,
+  #[clap(long = "uid", help = "Owner of folders and symlinks the mapping sets no uid for. Defaults to the user running fs-proxy")]
+  pub uid: Option<u32>,
+  #[clap(long = "gid", help = "Group of folders and symlinks the mapping sets no gid for. Defaults to the group running fs-proxy")]
+  pub gid: Option<u32>,
+  #[clap(long = "dir-mode", default_value = "755", value_parser = parse_mode, help = "Octal permissions of folders the mapping sets no mode for")]
+  pub dir_mode: u16,
+  #[clap(long = "entry-ttl", value_parser = parse_secs, help = "Seconds the kernel may cache name lookups. Defaults to 0, or a day with --immutable")]
+  pub entry_ttl: Option<Duration>,
+  #[clap(long = "attr-ttl", value_parser = parse_secs, help = "Seconds the kernel may cache attributes. Defaults to 0, or a day with --immutable")]
+  pub attr_ttl: Option<Duration>,
+  #[clap(long = "immutable", action, conflicts_with = "upper_dir", help = "Promise that blobs never change: long TTLs and page cache kept across opens. Mapping reloads may take up to the TTLs to show")]
+  pub immutable: bool,
+  #[clap(long = "prewarm-metadata", action, help = "Stat every blob in the background right after mounting, so the first listing of a folder is served from the cache")]
+  pub prewarm_metadata: bool,
+  #[clap(long = "shutdown-timeout", default_value = "10", value_parser = parse_secs, help = "Seconds reads still running on SIGTERM or SIGINT get to finish before the file handles are closed and the filesystem is unmounted")]
+  pub shutdown_timeout: Duration,
+  #[clap(long = "daemon", action, conflicts_with = "foreground", help = "Fork into the background. The command returns once the mount is ready, or with the exit code of the failed daemon. Logs on stderr stop there unless --log-file or --syslog is given")]
+  pub daemon: bool,
+  #[clap(long = "foreground", action, help = "Stay in the foreground, the default")]
+  pub foreground: bool,
+  #[clap(long = "pidfile", help = "Write the process id to this file once the mount is ready, removed on shutdown")]
+  pub pidfile: Option<String>,
+  #[clap(long = "ready-file", help = "Create this file once the mount is ready, removed on shutdown")]
+  pub ready_file: Option<String>,
+  #[clap(long = "ready-fd", help = "Inherited file descriptor to write READY=1 to and close once the mount is ready. systemd's NOTIFY_SOCKET is notified as well when set")]
+  pub ready_fd: Option<i32>,
+  #[clap(long = "log-level", env = "RUST_LOG", default_value = "info", value_parser = |spec: &str| Ok::<_, String>(LogFilter::parse(spec)), help = "Log filter in RUST_LOG syntax, e.g. warn,fs_proxy::reload=debug. Also applies to the FUSE library")]
+  pub log_level: LogFilter,
+  #[clap(long = "log-format", value_enum, default_value = "term", help = "Format of log lines")]
+  pub log_format: LogFormat,
+  #[clap(long = "log-file", conflicts_with = "syslog", help = "Append logs to this file instead of stderr")]
+  pub log_file: Option<String>,
+  #[clap(long = "syslog", action, help = "Send logs to the local syslog daemon instead of stderr")]
+  pub syslog: bool,
+}
+
+impl Args {
+  /// Resolves relative paths against `cwd`, for a daemon that leaves it
+  /// for `/`.
+  pub(crate) fn absolutize(&mut self, cwd: &std::path::Path) {
+    let absolute = |path: &mut String| {
+      if !path.starts_with('/') {
+        *path = cwd.join(&*path).to_string_lossy().into_owned();
+      }
+    };
+    absolute(&mut self.mountpoint);
+    let paths = [
+      &mut self.mapping_file,
+      &mut self.mapping_dir,
+      &mut self.control_socket,
+      &mut self.access_log,
+      &mut self.upper_dir,
+      &mut self.blob_root,
+      &mut self.pidfile,
+      &mut self.ready_file,
+      &mut self.log_file,
+    ];
+    for path in paths.into_iter().flatten() {
+      absolute(path);
+    }
+  }
+}
+
+fn parse_secs(secs: &str) -> Result<Duration, String> {
+  secs
+    .parse::<f64>()
+    .ok()
+    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+    .ok_or_else(|| format!("{:?} is not a number of seconds", secs))
+}
+
+fn parse_mode(mode: &str) -> Result<u16, String> {
+  u16::from_str_radix(mode.trim_start_matches("0o"), 8)
+    .ok()
+    .filter(|mode| *mode <= 0o7777)
+    .ok_or_else(|| format!("{:?} is not an octal mode", mode))
 }
 
 #[test]
@@ -25,6 +126,68 @@ fn test() {
       "--allow-root",
       "--mapping-file",
       "/tmp/mapping.json",
+      "--watch-interval",
+      "5",
+      "--blob-root",
+      "/default/blobs",
+      "--metrics-address",
+      "127.0.0.1:9100",
   ]);
   println!("args = {:?}", args);
-}
\ No newline at end of file
+  assert_eq!(args.watch_interval, Some(5));
+  assert_eq!(args.blob_root.as_deref(), Some("/default/blobs"));
+  assert_eq!(args.dir_mode, 0o755);
+  assert_eq!(args.metrics_address, Some(SocketAddr::from(([127, 0, 0, 1], 9100))));
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--watch-interval", "0"]).is_err());
+}
+
+#[test]
+fn test_dir_mode() {
+  let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--dir-mode", "0o750", "--uid", "0"]);
+  assert_eq!(args.dir_mode, 0o750);
+  assert_eq!(args.uid, Some(0));
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--dir-mode", "789"]).is_err());
+}
+
+#[test]
+fn test_immutable() {
+  let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--immutable", "--attr-ttl", "0.5"]);
+  assert!(args.immutable);
+  assert_eq!(args.attr_ttl, Some(Duration::from_millis(500)));
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--entry-ttl", "-1"]).is_err());
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--immutable", "--upper-dir", "/tmp/upper"]).is_err());
+}
+
+#[test]
+fn test_mapping_dir() {
+  let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-dir", "/tmp/mappings"]);
+  assert_eq!(args.mapping_dir.as_deref(), Some("/tmp/mappings"));
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello"]).is_err());
+  assert!(Args::try_parse_from(vec![
+      "fs-proxy",
+      "/tmp/hello",
+      "--mapping-dir",
+      "/tmp/mappings",
+      "--mapping-file",
+      "/tmp/mapping.json",
+  ]).is_err());
+}
+
+#[test]
+fn test_access_log() {
+  let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--access-log", "/tmp/access.log"]);
+  assert_eq!(args.access_log_max_size, 64);
+  let too_large = (u64::MAX >> 20) + 1;
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--access-log-max-size", &too_large.to_string()]).is_err());
+  assert!(Args::try_parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--access-log-max-size", "0"]).is_err());
+}
+
+#[test]
+fn test_absolutize() {
+  let mut args = Args::parse_from(vec!["fs-proxy", "mnt", "--mapping-file", "mapping.json", "--blob-root", "/blobs", "--daemon"]);
+  args.absolutize(std::path::Path::new("/srv"));
+  assert_eq!(args.mountpoint, "/srv/mnt");
+  assert_eq!(args.mapping_file.as_deref(), Some("/srv/mapping.json"));
+  assert_eq!(args.blob_root.as_deref(), Some("/blobs"));
+  assert_eq!(args.pidfile, None);
+}
@@ -0,0 +1,196 @@
+use std::fs::{DirBuilder, Permissions};
+use std::io::Error;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{error, info};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::RwLock;
+use crate::reload::reload;
+use crate::shutdown::{Reason, Shutdown};
+use crate::{read_mapping_file, Inner, LOG};
+
+/// One line of JSON sent to the control socket, e.g. `{"command": "status"}`
+/// or `{"command": "attach", "name": "<record-id>", "mapping_file": "/path/to/mapping.json"}`.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+  Status,
+  Handles,
+  Reload,
+  Attach { name: String, mapping_file: String },
+  Detach { name: String },
+  Unmount,
+}
+
+/// Accepts connections on `socket_path` and answers every command line with
+/// one line of JSON, `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+pub(crate) async fn serve(inner: Arc<RwLock<Inner>>, socket_path: String, shutdown: Arc<Shutdown>) {
+  // A socket left behind by a previous run would make bind fail
+  if let Ok(metadata) = std::fs::symlink_metadata(&socket_path) {
+    if metadata.file_type().is_socket() {
+      let _ = std::fs::remove_file(&socket_path);
+    }
+  }
+
+  let listener = match bind(&socket_path) {
+    Ok(listener) => listener,
+    Err(err) => {
+      error!(LOG, "Failed to bind control socket {}: {}", socket_path, err);
+      return;
+    }
+  };
+  info!(LOG, "Listening for control commands on {}", socket_path);
+
+  loop {
+    match listener.accept().await {
+      Ok((stream, _)) => {
+        tokio::spawn(handle(inner.clone(), shutdown.clone(), stream));
+      }
+      Err(err) => {
+        error!(LOG, "Failed to accept control connection: {}", err);
+      }
+    }
+  }
+}
+
+/// Binds the socket in a private folder next to `socket_path` and moves it
+/// there once it is restricted to the owner. Anyone who can connect can
+/// unmount, so the socket is never reachable under the umask's mode.
+fn bind(socket_path: &str) -> Result<UnixListener, Error> {
+  let path = std::path::Path::new(socket_path);
+  let private = path.with_file_name(format!(".fs-proxy-{}", std::process::id()));
+  DirBuilder::new().mode(0o700).create(&private)?;
+  let staged = private.join("s");
+  let bound = UnixListener::bind(&staged).and_then(|listener| {
+    std::fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+    std::fs::rename(&staged, path)?;
+    Ok(listener)
+  });
+  let _ = std::fs::remove_file(&staged);
+  let _ = std::fs::remove_dir(&private);
+  bound
+}
+
+async fn handle(inner: Arc<RwLock<Inner>>, shutdown: Arc<Shutdown>, stream: UnixStream) {
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = BufReader::new(reader).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let mut unmount = false;
+    let response = match serde_json::from_str::<Command>(&line) {
+      Ok(Command::Unmount) => {
+        unmount = true;
+        Ok(Value::Null)
+      }
+      Ok(command) => execute(&inner, command).await,
+      Err(err) => Err(format!("Invalid command: {}", err)),
+    };
+    let response = match response {
+      Ok(result) => json!({ "ok": true, "result": result }),
+      Err(err) => json!({ "ok": false, "error": err }),
+    };
+
+    let mut body = response.to_string();
+    body.push('\n');
+    if let Err(err) = writer.write_all(body.as_bytes()).await {
+      error!(LOG, "Failed to write control response: {}", err);
+      return;
+    }
+
+    if unmount {
+      info!(LOG, "Unmount requested through the control socket");
+      shutdown.request(Reason::Requested);
+      return;
+    }
+  }
+}
+
+async fn execute(inner: &RwLock<Inner>, command: Command) -> Result<Value, String> {
+  match command {
+    Command::Status => {
+      let inner = inner.read().await;
+      Ok(json!({
+        "source": inner.source.to_string(),
+        "attached": inner.attached,
+        "inodes": inner.inode_table.len(),
+        "open_handles": inner.file_handles.len(),
+        "open_dirs": inner.dir_handles.len(),
+      }))
+    }
+    Command::Handles => {
+      let inner = inner.read().await;
+      let handles: Vec<Value> = inner
+        .file_handles
+        .iter()
+        .map(|(fh, handle)| json!({ "fh": fh, "ino": handle.ino, "target": handle.target }))
+        .collect();
+      Ok(Value::Array(handles))
+    }
+    Command::Reload => {
+      let inodes = reload(inner, |_| Ok(())).await?;
+      Ok(json!({ "inodes": inodes }))
+    }
+    Command::Attach { name, mapping_file } => {
+      if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(format!("Invalid subtree name {:?}", name));
+      }
+      // A reload skips a broken attachment, only here can it be turned down
+      let path = mapping_file.clone();
+      tokio::task::spawn_blocking(move || read_mapping_file(&path))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| format!("Failed to read {}: {}", mapping_file, err))?;
+      let inodes = reload(inner, |attached| {
+        attached.insert(name, mapping_file);
+        Ok(())
+      })
+      .await?;
+      Ok(json!({ "inodes": inodes }))
+    }
+    Command::Detach { name } => {
+      let inodes = reload(inner, |attached| match attached.remove(&name) {
+        Some(_) => Ok(()),
+        None => Err(format!("No subtree attached as {:?}", name)),
+      })
+      .await?;
+      Ok(json!({ "inodes": inodes }))
+    }
+    Command::Unmount => unreachable!("unmount is handled by the connection"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::MetadataExt;
+
+  #[test]
+  fn test_parse_command() {
+    let command: Command = serde_json::from_str(r#"{"command": "status"}"#).unwrap();
+    assert!(matches!(command, Command::Status));
+    let command: Command =
+      serde_json::from_str(r#"{"command": "attach", "name": "r1", "mapping_file": "/tmp/r1.json"}"#).unwrap();
+    assert!(matches!(command, Command::Attach { name, .. } if name == "r1"));
+    assert!(serde_json::from_str::<Command>(r#"{"command": "format"}"#).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_bind() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let socket_path = dir.join("control.sock").to_string_lossy().into_owned();
+
+    let listener = bind(&socket_path).unwrap();
+    assert_eq!(std::fs::metadata(&socket_path).unwrap().mode() & 0o777, 0o600);
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 1);
+    let (connected, accepted) = tokio::join!(UnixStream::connect(&socket_path), listener.accept());
+    connected.unwrap();
+    accepted.unwrap();
+  }
+}
@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::{Error, Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use slog::{info, warn};
+use crate::args::Args;
+use crate::LOG;
+
+/// Forks into the background. The parent stays until the child reports the
+/// mount ready through the returned pipe, then exits with 0, or with the
+/// child's exit code when the child dies first. Must run before any thread
+/// is started, the child only inherits the calling thread. The child moves
+/// to `/` so that it keeps no directory busy, relative paths have to be
+/// resolved before, see `Args::absolutize`.
+pub(crate) fn daemonize() -> Result<File, Error> {
+  let mut fds = [0; 2];
+  // SAFETY: `fds` has room for both ends of the pipe
+  if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+    return Err(Error::last_os_error());
+  }
+  // SAFETY: pipe2 just opened both descriptors and nothing else owns them
+  let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+  // SAFETY: no other thread is running yet, see above
+  match unsafe { libc::fork() } {
+    -1 => Err(Error::last_os_error()),
+    0 => {
+      drop(read);
+      // SAFETY: plain syscalls, the path is a NUL terminated literal
+      unsafe {
+        if libc::setsid() < 0 {
+          return Err(Error::last_os_error());
+        }
+        if libc::chdir(c"/".as_ptr()) != 0 {
+          return Err(Error::last_os_error());
+        }
+      }
+      // stderr stays until the mount is ready, so that a failing start is
+      // reported on the terminal the parent still holds, see `Readiness`
+      to_dev_null(&[libc::STDIN_FILENO, libc::STDOUT_FILENO]);
+      Ok(write)
+    }
+    child => {
+      drop(write);
+      let mut ready = [0; 1];
+      if matches!(read.read(&mut ready), Ok(1)) {
+        std::process::exit(exitcode::OK);
+      }
+      let mut status = 0;
+      // SAFETY: `child` is our own child process
+      let waited = unsafe { libc::waitpid(child, &mut status, 0) };
+      if waited == child && libc::WIFEXITED(status) {
+        std::process::exit(libc::WEXITSTATUS(status));
+      }
+      std::process::exit(exitcode::SOFTWARE);
+    }
+  }
+}
+
+/// Points every descriptor in `fds` at /dev/null.
+fn to_dev_null(fds: &[RawFd]) {
+  // SAFETY: plain syscalls on descriptors this process owns
+  unsafe {
+    let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+    if null >= 0 {
+      for fd in fds {
+        libc::dup2(null, *fd);
+      }
+      libc::close(null);
+    }
+  }
+}
+
+/// Everyone waiting for the mount to come up: the parent of a daemon, a
+/// supervising systemd, a pidfile or ready-file watcher and whoever passed
+/// `--ready-fd`.
+pub(crate) struct Readiness {
+  daemon: Option<File>,
+  /// A daemon logging to stderr lets go of the terminal once ready.
+  detach_stderr: bool,
+  pidfile: Option<String>,
+  ready_file: Option<String>,
+  ready_fd: Option<RawFd>,
+}
+
+impl Readiness {
+  pub(crate) fn from_args(args: &Args, daemon: Option<File>) -> Self {
+    Readiness {
+      detach_stderr: daemon.is_some() && args.log_file.is_none() && !args.syslog,
+      daemon,
+      pidfile: args.pidfile.clone(),
+      ready_file: args.ready_file.clone(),
+      ready_fd: args.ready_fd,
+    }
+  }
+
+  /// Called once the kernel finished the FUSE handshake, i.e. the mount
+  /// serves requests.
+  pub(crate) fn ready(&mut self) {
+    let pid = std::process::id();
+    if let Some(pidfile) = &self.pidfile {
+      if let Err(err) = std::fs::write(pidfile, format!("{}\n", pid)) {
+        warn!(LOG, "Failed to write pidfile {}: {}", pidfile, err);
+      }
+    }
+    if let Some(ready_file) = &self.ready_file {
+      if let Err(err) = std::fs::write(ready_file, format!("{}\n", pid)) {
+        warn!(LOG, "Failed to write ready-file {}: {}", ready_file, err);
+      }
+    }
+    if let Some(fd) = self.ready_fd.take() {
+      let message = b"READY=1\n";
+      // SAFETY: `fd` was handed to us to write to, a wrong number only
+      // makes the calls fail
+      let written = unsafe {
+        let written = libc::write(fd, message.as_ptr().cast(), message.len());
+        libc::close(fd);
+        written
+      };
+      if written != message.len() as isize {
+        warn!(LOG, "Failed to signal readiness on fd {}: {}", fd, Error::last_os_error());
+      }
+    }
+    sd_notify(&format!("READY=1\nMAINPID={}", pid));
+    info!(LOG, "Mount is ready");
+    if let Some(mut daemon) = self.daemon.take() {
+      if let Err(err) = daemon.write_all(b"1") {
+        warn!(LOG, "Failed to signal readiness to the parent process: {}", err);
+      }
+    }
+    // The parent exits now and the terminal may go with it
+    if self.detach_stderr {
+      to_dev_null(&[libc::STDERR_FILENO]);
+    }
+  }
+
+  pub(crate) fn stopping(&self) {
+    sd_notify("STOPPING=1");
+  }
+
+  /// Removes the files `ready` wrote, they would claim a mount that is gone.
+  pub(crate) fn cleanup(&self) {
+    for path in self.pidfile.iter().chain(&self.ready_file) {
+      let _ = std::fs::remove_file(path);
+    }
+  }
+}
+
+/// Sends `state` to systemd when it supervises the process with
+/// `Type=notify`. Only filesystem sockets are supported, systemd uses one
+/// unless configured otherwise.
+fn sd_notify(state: &str) {
+  let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+    return;
+  };
+  if socket.to_string_lossy().starts_with('@') {
+    warn!(LOG, "Abstract NOTIFY_SOCKET {:?} is not supported", socket);
+    return;
+  }
+  let sent = UnixDatagram::unbound().and_then(|datagram| datagram.send_to(state.as_bytes(), &socket));
+  if let Err(err) = sent {
+    warn!(LOG, "Failed to notify systemd through {:?}: {}", socket, err);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ready() {
+    let dir = tempfile::tempdir().unwrap();
+    let pidfile = dir.path().join("fs-proxy.pid");
+    let ready_file = dir.path().join("ready");
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+
+    let mut readiness = Readiness {
+      daemon: None,
+      detach_stderr: false,
+      pidfile: Some(pidfile.to_string_lossy().into_owned()),
+      ready_file: Some(ready_file.to_string_lossy().into_owned()),
+      ready_fd: Some(fds[1]),
+    };
+    readiness.ready();
+    let pid = format!("{}\n", std::process::id());
+    assert_eq!(std::fs::read_to_string(&pidfile).unwrap(), pid);
+    assert_eq!(std::fs::read_to_string(&ready_file).unwrap(), pid);
+    let mut message = String::new();
+    pipe.read_to_string(&mut message).unwrap();
+    assert_eq!(message, "READY=1\n");
+
+    readiness.cleanup();
+    assert!(!pidfile.exists());
+    assert!(!ready_file.exists());
+  }
+
+  #[test]
+  fn test_detach_stderr() {
+    use clap::Parser;
+    let parse = |extra: &[&str]| Args::parse_from(["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json"].iter().chain(extra));
+    let daemon = || Some(File::open("/dev/null").unwrap());
+    assert!(Readiness::from_args(&parse(&["--daemon"]), daemon()).detach_stderr);
+    assert!(!Readiness::from_args(&parse(&["--daemon", "--log-file", "/tmp/fs-proxy.log"]), daemon()).detach_stderr);
+    assert!(!Readiness::from_args(&parse(&["--daemon", "--syslog"]), daemon()).detach_stderr);
+    assert!(!Readiness::from_args(&parse(&[]), None).detach_stderr);
+  }
+}
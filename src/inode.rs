@@ -1,8 +1,17 @@
-use crate::mapping::Path;
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use crate::archive::{self, ArchiveMember};
+use crate::mapping::{Metadata, Path};
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Deref;
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+/// What an inode is, the part of it the kernel never expects to change under
+/// the same number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Folder,
+    Symlink,
+}
 
 #[derive(Debug)]
 pub enum INode {
@@ -11,26 +20,31 @@ pub enum INode {
         parent: u64,
         name: String,
         target: String,
+        member: Option<ArchiveMember>,
+        /// Expected digest of `target`, from the mapping or a blob reference.
+        sha256: Option<String>,
+        meta: Metadata,
     },
     Folder {
         ino: u64,
         parent: u64,
         name: String,
-        entries: BTreeMap<String, Rc<RefCell<INode>>>,
+        entries: BTreeMap<String, Arc<RwLock<INode>>>,
+        meta: Metadata,
+    },
+    Symlink {
+        ino: u64,
+        parent: u64,
+        name: String,
+        target: String,
     },
 }
 
 impl INode {
-    fn lookup(&self, name: String) -> Option<Rc<RefCell<INode>>> {
+    fn lookup(&self, name: String) -> Option<Arc<RwLock<INode>>> {
         match self {
-            INode::File { .. } => None,
-            INode::Folder { entries, .. } => {
-                if let Some(entry) = entries.get(&name) {
-                    Some(entry.clone())
-                } else {
-                    None
-                }
-            }
+            INode::File { .. } | INode::Symlink { .. } => None,
+            INode::Folder { entries, .. } => entries.get(&name).cloned(),
         }
     }
 
@@ -38,6 +52,19 @@ impl INode {
         match self {
             INode::File { name, .. } => name,
             INode::Folder { name, .. } => name,
+            INode::Symlink { name, .. } => name,
+        }
+    }
+
+    pub fn is_folder(&self) -> bool {
+        matches!(self, INode::Folder { .. })
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            INode::File { .. } => Kind::File,
+            INode::Folder { .. } => Kind::Folder,
+            INode::Symlink { .. } => Kind::Symlink,
         }
     }
 
@@ -45,6 +72,15 @@ impl INode {
         match self {
             INode::File { ino, .. } => *ino,
             INode::Folder { ino, .. } => *ino,
+            INode::Symlink { ino, .. } => *ino,
+        }
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        match self {
+            INode::File { name: n, .. } => *n = name,
+            INode::Folder { name: n, .. } => *n = name,
+            INode::Symlink { name: n, .. } => *n = name,
         }
     }
 
@@ -52,6 +88,7 @@ impl INode {
         match self {
             INode::File { ino: i, .. } => *i = ino,
             INode::Folder { ino: i, .. } => *i = ino,
+            INode::Symlink { ino: i, .. } => *i = ino,
         }
     }
 
@@ -59,6 +96,7 @@ impl INode {
         match self {
             INode::File { parent: p, .. } => *p = parent,
             INode::Folder { parent: p, .. } => *p = parent,
+            INode::Symlink { parent: p, .. } => *p = parent,
         }
     }
 
@@ -66,16 +104,17 @@ impl INode {
         match self {
             INode::File { parent, .. } => *parent,
             INode::Folder { parent, .. } => *parent,
+            INode::Symlink { parent, .. } => *parent,
         }
     }
 
     fn auto_set_parent(&mut self, parent: u64) {
         self.set_parent(parent);
         match self {
-            INode::File { .. } => {}
+            INode::File { .. } | INode::Symlink { .. } => {}
             INode::Folder { ino, entries, .. } => {
-                for (_, entry) in entries {
-                    entry.borrow_mut().auto_set_parent(*ino);
+                for entry in entries.values() {
+                    entry.write().unwrap().auto_set_parent(*ino);
                 }
             }
         }
@@ -85,88 +124,262 @@ impl INode {
 impl From<Path> for INode {
     fn from(value: Path) -> Self {
         match value {
-            Path::File { name, path } => INode::File {
+            Path::File {
+                name,
+                path,
+                member,
+                sha256,
+                meta,
+            } => INode::File {
                 ino: 0,
                 parent: 0,
                 name,
                 target: path,
+                member,
+                sha256,
+                meta,
             },
-            Path::Folder { name, paths } => {
+            Path::Folder { name, paths, meta } => {
                 let mut entries = BTreeMap::new();
                 for (name, path) in paths {
-                    entries.insert(name, Rc::new(RefCell::new(path.into())));
+                    entries.insert(name, Arc::new(RwLock::new(path.into())));
                 }
                 INode::Folder {
                     ino: 0,
                     parent: 0,
                     name,
                     entries,
+                    meta,
                 }
             }
+            archive @ (Path::Zip { .. } | Path::Tar { .. }) => {
+                let mut folder = archive;
+                archive::expand(&mut folder);
+                folder.into()
+            }
+            Path::Symlink { name, target } => INode::Symlink {
+                ino: 0,
+                parent: 0,
+                name,
+                target,
+            },
+            Path::Blob { .. } => unreachable!("blobs are resolved when the mapping is loaded"),
         }
     }
 }
 
 pub trait INodeOps {
-    fn list_recursively(&self) -> Vec<Rc<RefCell<INode>>>;
-    fn list_current(&self) -> Vec<Rc<RefCell<INode>>>;
+    fn list_current(&self) -> Vec<Arc<RwLock<INode>>>;
+    fn list_with_paths(&self) -> Vec<(String, Arc<RwLock<INode>>)>;
 }
 
-impl INodeOps for Rc<RefCell<INode>> {
-    fn list_recursively(&self) -> Vec<Rc<RefCell<INode>>> {
-        match self.borrow().deref() {
-            INode::File { .. } => vec![self.clone()],
-            INode::Folder { entries, .. } => {
-                let mut res = vec![self.clone()];
-                let mut deep: Vec<Rc<RefCell<INode>>> = entries
-                    .values()
-                    .flat_map(|entry| entry.list_recursively())
-                    .collect();
-                res.append(&mut deep);
-                res
-            }
+impl INodeOps for Arc<RwLock<INode>> {
+    fn list_current(&self) -> Vec<Arc<RwLock<INode>>> {
+        match self.read().unwrap().deref() {
+            INode::File { .. } | INode::Symlink { .. } => vec![],
+            INode::Folder { entries, .. } => entries.values().cloned().collect(),
         }
     }
 
-    fn list_current(&self) -> Vec<Rc<RefCell<INode>>> {
-        match self.borrow().deref() {
-            INode::File { .. } => vec![],
-            INode::Folder { entries, .. } => entries.values().cloned().collect(),
+    fn list_with_paths(&self) -> Vec<(String, Arc<RwLock<INode>>)> {
+        fn walk(
+            inode: &Arc<RwLock<INode>>,
+            path: String,
+            res: &mut Vec<(String, Arc<RwLock<INode>>)>,
+        ) {
+            res.push((path.clone(), inode.clone()));
+            if let INode::Folder { entries, .. } = inode.read().unwrap().deref() {
+                for (name, entry) in entries {
+                    walk(entry, format!("{}/{}", path.trim_end_matches('/'), name), res);
+                }
+            }
         }
+
+        let mut res = vec![];
+        walk(self, String::from("/"), &mut res);
+        res
     }
 }
 
+#[derive(Debug)]
 pub struct INodeTable {
-    table: Vec<Rc<RefCell<INode>>>,
-    // root: Rc<RefCell<INode>>,
+    table: BTreeMap<u64, Arc<RwLock<INode>>>,
+    root: Arc<RwLock<INode>>,
 }
 
 impl INodeTable {
-    pub fn lookup(&self, ino: u64, name: String) -> Option<Rc<RefCell<INode>>> {
+    /// Entry `name` of the folder `ino`. `.` is the folder itself and `..` its
+    /// parent, the root being its own parent.
+    pub fn lookup(&self, ino: u64, name: String) -> Option<Arc<RwLock<INode>>> {
+        let folder = self.table.get(&ino)?;
+        let folder_ref = folder.read().unwrap();
+        if !folder_ref.is_folder() {
+            return None;
+        }
+        match name.as_str() {
+            "." => Some(folder.clone()),
+            ".." => self.get_by_ino(folder_ref.get_parent()),
+            _ => folder_ref.lookup(name),
+        }
+    }
+
+    pub fn get_by_ino(&self, ino: u64) -> Option<Arc<RwLock<INode>>> {
+        self.table.get(&ino).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Inode number and blob of every file.
+    pub fn files(&self) -> Vec<(u64, String)> {
         self.table
-            .get(ino as usize - 1)
-            .and_then(|inode| inode.borrow().lookup(name))
+            .iter()
+            .filter_map(|(ino, inode)| match inode.read().unwrap().deref() {
+                INode::File { target, .. } => Some((*ino, target.clone())),
+                _ => None,
+            })
+            .collect()
     }
 
-    pub fn get_by_ino(&self, ino: u64) -> Option<Rc<RefCell<INode>>> {
-        self.table.get(ino as usize - 1).cloned()
+    /// Full virtual path of `ino`, following the parent links up to the root.
+    pub fn path_of(&self, ino: u64) -> Option<String> {
+        let mut names = vec![];
+        let mut current = ino;
+        while current != ROOT_INO {
+            let inode = self.table.get(&current)?.read().unwrap();
+            names.push(inode.get_name().clone());
+            current = inode.get_parent();
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
     }
-}
 
-impl From<Rc<RefCell<INode>>> for INodeTable {
-    fn from(root: Rc<RefCell<INode>>) -> Self {
-        let table = root.list_recursively();
-        for (idx, inode) in table.iter().enumerate() {
-            inode.borrow_mut().set_ino(idx as u64 + 1)
+    /// Adds `inode` to the folder `parent`, replacing any entry with the same
+    /// name. A new inode (ino 0) is numbered from its path, a moved one keeps
+    /// its number and those of its subtree.
+    pub fn insert(&mut self, parent: u64, inode: Arc<RwLock<INode>>) -> Option<u64> {
+        let folder = self.table.get(&parent)?.clone();
+        if !folder.read().unwrap().is_folder() {
+            return None;
         }
+        let name = inode.read().unwrap().get_name().clone();
+        self.remove(parent, &name);
 
-        root.borrow_mut().auto_set_parent(0);
+        let ino = {
+            let mut inode_mut = inode.write().unwrap();
+            if inode_mut.get_ino() == 0 {
+                let path = format!("{}/{}", self.path_of(parent)?.trim_end_matches('/'), name);
+                let ino = fresh_ino(&self.table, &path, inode_mut.kind());
+                inode_mut.set_ino(ino);
+            }
+            inode_mut.set_parent(parent);
+            inode_mut.get_ino()
+        };
 
-        INodeTable {
-            // root: root.clone(),
-            table,
+        if let INode::Folder { entries, .. } = &mut *folder.write().unwrap() {
+            entries.insert(name, inode.clone());
+        }
+        for (_, entry) in inode.list_with_paths() {
+            let entry_ino = entry.read().unwrap().get_ino();
+            self.table.insert(entry_ino, entry);
         }
+        Some(ino)
+    }
+
+    /// Detaches `name` from the folder `parent` and drops its whole subtree
+    /// from the table.
+    pub fn remove(&mut self, parent: u64, name: &str) -> Option<Arc<RwLock<INode>>> {
+        let folder = self.table.get(&parent)?.clone();
+        let removed = match &mut *folder.write().unwrap() {
+            INode::Folder { entries, .. } => entries.remove(name)?,
+            INode::File { .. } | INode::Symlink { .. } => return None,
+        };
+        for (_, inode) in removed.list_with_paths() {
+            let ino = inode.read().unwrap().get_ino();
+            self.table.remove(&ino);
+        }
+        Some(removed)
+    }
+
+    /// Builds the table for a freshly loaded tree. Paths that still exist
+    /// with the same kind keep their inode number, even one that was bumped
+    /// by a hash collision, everything else is numbered by `path_ino`.
+    pub fn rebuild(&self, root: Arc<RwLock<INode>>) -> INodeTable {
+        let previous = self
+            .root
+            .list_with_paths()
+            .into_iter()
+            .map(|(path, inode)| {
+                let inode = inode.read().unwrap();
+                (path, (inode.get_ino(), inode.kind()))
+            })
+            .collect();
+        Self::assign(root, &previous)
     }
+
+    fn assign(root: Arc<RwLock<INode>>, previous: &HashMap<String, (u64, Kind)>) -> INodeTable {
+        let mut table = BTreeMap::new();
+        let mut fresh = vec![];
+        for (path, inode) in root.list_with_paths() {
+            let kind = inode.read().unwrap().kind();
+            match previous.get(&path) {
+                Some((ino, was)) if *was == kind => {
+                    inode.write().unwrap().set_ino(*ino);
+                    table.insert(*ino, inode);
+                }
+                _ => fresh.push((path, kind, inode)),
+            }
+        }
+
+        for (path, kind, inode) in fresh {
+            let ino = if path == "/" {
+                ROOT_INO
+            } else {
+                fresh_ino(&table, &path, kind)
+            };
+            inode.write().unwrap().set_ino(ino);
+            table.insert(ino, inode);
+        }
+
+        root.write().unwrap().auto_set_parent(ROOT_INO);
+
+        INodeTable { table, root }
+    }
+}
+
+impl From<Arc<RwLock<INode>>> for INodeTable {
+    fn from(root: Arc<RwLock<INode>>) -> Self {
+        INodeTable::assign(root, &HashMap::new())
+    }
+}
+
+/// Inode number of the mount root, fixed by the FUSE protocol.
+pub const ROOT_INO: u64 = 1;
+
+/// `path_ino`, bumped past the root and any number already in `table`.
+fn fresh_ino(table: &BTreeMap<u64, Arc<RwLock<INode>>>, path: &str, kind: Kind) -> u64 {
+    let mut ino = path_ino(path, kind);
+    while ino <= ROOT_INO || table.contains_key(&ino) {
+        ino = ino.wrapping_add(1);
+    }
+    ino
+}
+
+/// FNV-1a over the kind and the full virtual path. Unlike `DefaultHasher`
+/// it is guaranteed to give the same numbers across Rust releases, so the
+/// inode of a path only depends on the path itself.
+fn path_ino(path: &str, kind: Kind) -> u64 {
+    let kind: &[u8] = match kind {
+        Kind::File => b"f:",
+        Kind::Folder => b"d:",
+        Kind::Symlink => b"l:",
+    };
+    kind.iter()
+        .chain(path.as_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
 }
 
 #[cfg(test)]
@@ -175,37 +388,52 @@ mod tests {
     use std::fs;
 
     fn fake_inode_tree() -> INode {
-        let hosts = Rc::new(RefCell::new(INode::File {
+        let hosts = Arc::new(RwLock::new(INode::File {
             ino: 0,
             parent: 0,
             name: String::from("hosts"),
             target: String::from("/etc/hosts"),
+            member: None,
+            sha256: None,
+            meta: Metadata::default(),
         }));
-        let passwd = Rc::new(RefCell::new(INode::File {
+        let passwd = Arc::new(RwLock::new(INode::File {
             ino: 0,
             parent: 0,
             name: String::from("passwd"),
             target: String::from("/etc/passwd"),
+            member: None,
+            sha256: None,
+            meta: Metadata::default(),
         }));
-        let shadow = Rc::new(RefCell::new(INode::File {
+        let shadow = Arc::new(RwLock::new(INode::File {
             ino: 0,
             parent: 0,
             name: String::from("shadow"),
             target: String::from("/etc/shadow"),
+            member: None,
+            sha256: None,
+            meta: Metadata::default(),
         }));
-        let group = Rc::new(RefCell::new(INode::File {
+        let group = Arc::new(RwLock::new(INode::File {
             ino: 0,
             parent: 0,
             name: String::from("group"),
             target: String::from("/etc/group"),
+            member: None,
+            sha256: None,
+            meta: Metadata::default(),
         }));
-        let subfile = Rc::new(RefCell::new(INode::File {
+        let subfile = Arc::new(RwLock::new(INode::File {
             ino: 0,
             parent: 0,
             name: String::from("subfile"),
             target: String::from("/etc/subfile"),
+            member: None,
+            sha256: None,
+            meta: Metadata::default(),
         }));
-        let subfolder = Rc::new(RefCell::new(INode::Folder {
+        let subfolder = Arc::new(RwLock::new(INode::Folder {
             ino: 0,
             parent: 0,
             name: String::from("subfolder"),
@@ -214,9 +442,10 @@ mod tests {
                 es.insert(String::from("subfile"), subfile);
                 es
             },
+            meta: Metadata::default(),
         }));
 
-        let etc = Rc::new(RefCell::new(INode::Folder {
+        let etc = Arc::new(RwLock::new(INode::Folder {
             ino: 0,
             parent: 0,
             name: String::from("etc"),
@@ -229,6 +458,7 @@ mod tests {
                 es.insert(String::from("subfolder"), subfolder);
                 es
             },
+            meta: Metadata::default(),
         }));
         INode::Folder {
             ino: 0,
@@ -239,12 +469,13 @@ mod tests {
                 es.insert(String::from("etc"), etc);
                 es
             },
+            meta: Metadata::default(),
         }
     }
 
     #[test]
     fn test() {
-        let root = Rc::new(RefCell::new(fake_inode_tree()));
+        let root = Arc::new(RwLock::new(fake_inode_tree()));
 
         let table = INodeTable::from(root);
         println!("list_files: {:?}", table);
@@ -254,11 +485,194 @@ mod tests {
     fn test_mapping_file() {
         let mapping_tree = fs::read_to_string("res/mapping-tree.json").unwrap();
         let path: Path = serde_json::from_str(&mapping_tree).unwrap();
-        let root = Rc::new(RefCell::new(path.into()));
+        let root = Arc::new(RwLock::new(path.into()));
         let table = INodeTable::from(root);
-        let root_ino = table.root.borrow().get_ino();
+        let root_ino = table.root.read().unwrap().get_ino();
         let file = table.lookup(root_ino, String::from("Surge.app"));
 
         assert!(file.is_some());
     }
+
+    #[test]
+    fn test_rebuild_keeps_ino() {
+        let table = INodeTable::from(Arc::new(RwLock::new(fake_inode_tree())));
+        let etc_ino = table.lookup(ROOT_INO, String::from("etc")).unwrap().read().unwrap().get_ino();
+        let hosts_ino = table.lookup(etc_ino, String::from("hosts")).unwrap().read().unwrap().get_ino();
+
+        let root = Arc::new(RwLock::new(fake_inode_tree()));
+        if let INode::Folder { entries, .. } = root.read().unwrap().deref() {
+            let etc = entries.get("etc").unwrap();
+            if let INode::Folder { entries, .. } = &mut *etc.write().unwrap() {
+                entries.insert(
+                    String::from("fstab"),
+                    Arc::new(RwLock::new(INode::File {
+                        ino: 0,
+                        parent: 0,
+                        name: String::from("fstab"),
+                        target: String::from("/etc/fstab"),
+                        member: None,
+                        sha256: None,
+                        meta: Metadata::default(),
+                    })),
+                );
+            }
+        }
+        let rebuilt = table.rebuild(root);
+
+        assert_eq!(rebuilt.len(), table.len() + 1);
+        let etc = rebuilt.lookup(ROOT_INO, String::from("etc")).unwrap();
+        assert_eq!(etc.read().unwrap().get_ino(), etc_ino);
+        let hosts = rebuilt.lookup(etc_ino, String::from("hosts")).unwrap();
+        assert_eq!(hosts.read().unwrap().get_ino(), hosts_ino);
+        let fstab = rebuilt.lookup(etc_ino, String::from("fstab")).unwrap();
+        assert!(table.get_by_ino(fstab.read().unwrap().get_ino()).is_none());
+    }
+
+    #[test]
+    fn test_rebuild_renumbers_changed_kind() {
+        let replace_hosts = |hosts: INode| {
+            let root = Arc::new(RwLock::new(fake_inode_tree()));
+            if let INode::Folder { entries, .. } = root.read().unwrap().deref() {
+                if let INode::Folder { entries, .. } = &mut *entries.get("etc").unwrap().write().unwrap() {
+                    entries.insert(String::from("hosts"), Arc::new(RwLock::new(hosts)));
+                }
+            }
+            root
+        };
+        let hosts_ino = |table: &INodeTable| {
+            let etc_ino = table.lookup(ROOT_INO, String::from("etc")).unwrap().read().unwrap().get_ino();
+            let hosts = table.lookup(etc_ino, String::from("hosts")).unwrap();
+            let hosts = hosts.read().unwrap();
+            (hosts.get_ino(), hosts.kind())
+        };
+
+        let table = INodeTable::from(Arc::new(RwLock::new(fake_inode_tree())));
+        let (file_ino, kind) = hosts_ino(&table);
+        assert_eq!(kind, Kind::File);
+
+        let symlink = INode::Symlink {
+            ino: 0,
+            parent: 0,
+            name: String::from("hosts"),
+            target: String::from("/etc/hosts"),
+        };
+        let table = table.rebuild(replace_hosts(symlink));
+        let (symlink_ino, kind) = hosts_ino(&table);
+        assert_eq!(kind, Kind::Symlink);
+        assert_ne!(symlink_ino, file_ino);
+
+        let table = table.rebuild(Arc::new(RwLock::new(fake_inode_tree())));
+        let (ino, kind) = hosts_ino(&table);
+        assert_eq!(kind, Kind::File);
+        assert_ne!(ino, symlink_ino);
+    }
+
+    #[test]
+    fn test_ino_independent_of_siblings() {
+        let table = INodeTable::from(Arc::new(RwLock::new(fake_inode_tree())));
+
+        let root = Arc::new(RwLock::new(fake_inode_tree()));
+        if let INode::Folder { entries, .. } = &mut *root.write().unwrap() {
+            entries.insert(
+                String::from("bin"),
+                Arc::new(RwLock::new(INode::Folder {
+                    ino: 0,
+                    parent: 0,
+                    name: String::from("bin"),
+                    entries: BTreeMap::new(),
+                    meta: Metadata::default(),
+                })),
+            );
+        }
+        let other = INodeTable::from(root);
+
+        for (path, inode) in table.root.list_with_paths() {
+            let ino = inode.read().unwrap().get_ino();
+            let same = other
+                .root
+                .list_with_paths()
+                .into_iter()
+                .find(|(other_path, _)| *other_path == path)
+                .unwrap();
+            assert_eq!(same.1.read().unwrap().get_ino(), ino, "{}", path);
+        }
+        assert_eq!(table.root.read().unwrap().get_ino(), ROOT_INO);
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut table = INodeTable::from(Arc::new(RwLock::new(fake_inode_tree())));
+        let etc_ino = table.lookup(ROOT_INO, String::from("etc")).unwrap().read().unwrap().get_ino();
+        let subfolder = table.lookup(etc_ino, String::from("subfolder")).unwrap();
+        let subfolder_ino = subfolder.read().unwrap().get_ino();
+        assert_eq!(table.path_of(subfolder_ino).unwrap(), "/etc/subfolder");
+
+        let ino = table
+            .insert(
+                subfolder_ino,
+                Arc::new(RwLock::new(INode::File {
+                    ino: 0,
+                    parent: 0,
+                    name: String::from("new"),
+                    target: String::from("/tmp/new"),
+                    member: None,
+                    sha256: None,
+                    meta: Metadata::default(),
+                })),
+            )
+            .unwrap();
+        assert_eq!(table.path_of(ino).unwrap(), "/etc/subfolder/new");
+
+        let moved = table.remove(etc_ino, "subfolder").unwrap();
+        assert!(table.get_by_ino(ino).is_none());
+        moved.write().unwrap().set_name(String::from("moved"));
+        assert_eq!(table.insert(ROOT_INO, moved), Some(subfolder_ino));
+        assert_eq!(table.path_of(ino).unwrap(), "/moved/new");
+        assert!(table.lookup(etc_ino, String::from("subfolder")).is_none());
+    }
+
+    #[test]
+    fn test_traversal() {
+        let table = INodeTable::from(Arc::new(RwLock::new(fake_inode_tree())));
+        let ino_of = |inode: Option<Arc<RwLock<INode>>>| inode.unwrap().read().unwrap().get_ino();
+
+        assert_eq!(ino_of(table.lookup(ROOT_INO, String::from("."))), ROOT_INO);
+        assert_eq!(ino_of(table.lookup(ROOT_INO, String::from(".."))), ROOT_INO);
+        assert!(table.get_by_ino(0).is_none());
+        assert!(table.lookup(0, String::from("..")).is_none());
+
+        // Down to /etc/subfolder and back up
+        let etc_ino = ino_of(table.lookup(ROOT_INO, String::from("etc")));
+        let subfolder_ino = ino_of(table.lookup(etc_ino, String::from("subfolder")));
+        assert_eq!(ino_of(table.lookup(subfolder_ino, String::from("."))), subfolder_ino);
+        assert_eq!(ino_of(table.lookup(subfolder_ino, String::from(".."))), etc_ino);
+        assert_eq!(ino_of(table.lookup(etc_ino, String::from(".."))), ROOT_INO);
+
+        // Files have no entries, not even `.` and `..`
+        let subfile = table.lookup(subfolder_ino, String::from("subfile")).unwrap();
+        let subfile = subfile.read().unwrap();
+        assert_eq!(subfile.get_parent(), subfolder_ino);
+        assert!(table.lookup(subfile.get_ino(), String::from("..")).is_none());
+        assert!(table.lookup(subfile.get_ino(), String::from(".")).is_none());
+        assert_eq!(table.path_of(subfile.get_ino()).unwrap(), "/etc/subfolder/subfile");
+    }
+
+    #[test]
+    fn test_symlink() {
+        let json = r#"{"type": "Folder", "name": "/", "paths": {
+            "Current": {"type": "Symlink", "name": "Current", "target": "Versions/A"},
+            "Versions": {"type": "Folder", "name": "Versions", "paths": {}}
+        }}"#;
+        let path: Path = serde_json::from_str(json).unwrap();
+        let table = INodeTable::from(Arc::new(RwLock::new(INode::from(path))));
+        let current = table.lookup(ROOT_INO, String::from("Current")).unwrap();
+        let current = current.read().unwrap();
+        let INode::Symlink { ino, parent, target, .. } = current.deref() else {
+            panic!("Current should be a symlink");
+        };
+        assert_eq!(target, "Versions/A");
+        assert_eq!(*parent, ROOT_INO);
+        assert_eq!(table.path_of(*ino).unwrap(), "/Current");
+        assert!(table.lookup(*ino, String::from("anything")).is_none());
+    }
 }
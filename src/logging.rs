@@ -0,0 +1,367 @@
+use std::ffi::CString;
+use std::fmt::Arguments;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use slog::{o, Drain, Key, Level, Logger, Never, OwnedKVList, Record, Serializer, KV};
+use slog_async::{Async, AsyncGuard};
+use crate::args::Args;
+
+lazy_static! {
+  /// The logger `init` built, picked up by the first use of `LOG`.
+  static ref CONFIGURED: Mutex<Option<Logger>> = Mutex::new(None);
+  /// Threads writing out the queued records, joined by `flush`.
+  static ref WRITERS: Mutex<Vec<AsyncGuard>> = Mutex::new(vec![]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFormat {
+  /// Human readable lines.
+  Term,
+  /// One JSON object per line.
+  Json,
+}
+
+/// Where log lines go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LogTarget {
+  Stderr,
+  File(String),
+  Syslog,
+}
+
+impl LogTarget {
+  pub(crate) fn from_args(args: &Args) -> Self {
+    match (&args.log_file, args.syslog) {
+      (Some(path), _) => LogTarget::File(path.clone()),
+      (None, true) => LogTarget::Syslog,
+      (None, false) => LogTarget::Stderr,
+    }
+  }
+}
+
+/// Which records pass, in `RUST_LOG` syntax: a comma separated list of
+/// `level` and `module=level` directives, e.g. `warn,fs_proxy::reload=debug`.
+/// The longest matching module wins, a bare module name enables everything
+/// in it. As with env_logger, modules not named are off unless a bare level
+/// is given, and an empty spec means `info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LogFilter {
+  default: Option<Level>,
+  modules: Vec<(String, Option<Level>)>,
+  /// Directives that could not be parsed and were left out.
+  invalid: Vec<String>,
+}
+
+impl LogFilter {
+  pub(crate) fn parse(spec: &str) -> LogFilter {
+    let mut filter = LogFilter { default: None, modules: vec![], invalid: vec![] };
+    let mut default = None;
+    for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+      match directive.split_once('=') {
+        Some((module, level)) => match parse_level(level) {
+          Ok(level) if !module.is_empty() => filter.modules.push((module.to_string(), level)),
+          _ => filter.invalid.push(directive.to_string()),
+        },
+        None => match parse_level(directive) {
+          Ok(level) => default = Some(level),
+          Err(_) => filter.modules.push((directive.to_string(), Some(Level::Trace))),
+        },
+      }
+    }
+    filter.default = match default {
+      Some(level) => level,
+      None if filter.modules.is_empty() => Some(Level::Info),
+      None => None,
+    };
+    filter
+  }
+
+  /// Directives `parse` left out, to be warned about once logging is up.
+  pub(crate) fn invalid(&self) -> &[String] {
+    &self.invalid
+  }
+
+  pub(crate) fn enabled(&self, module: &str, level: Level) -> bool {
+    let max = self
+      .modules
+      .iter()
+      .filter(|(prefix, _)| {
+        module == prefix || (module.starts_with(prefix.as_str()) && module[prefix.len()..].starts_with("::"))
+      })
+      .max_by_key(|(prefix, _)| prefix.len())
+      .map_or(self.default, |(_, level)| *level);
+    matches!(max, Some(max) if level.is_at_least(max))
+  }
+
+  /// Most verbose level any directive lets through.
+  fn max_level(&self) -> log::LevelFilter {
+    let levels = self.modules.iter().map(|(_, level)| *level).chain([self.default]);
+    match levels.flatten().max_by_key(|level| level.as_usize()) {
+      None => log::LevelFilter::Off,
+      Some(Level::Critical | Level::Error) => log::LevelFilter::Error,
+      Some(Level::Warning) => log::LevelFilter::Warn,
+      Some(Level::Info) => log::LevelFilter::Info,
+      Some(Level::Debug) => log::LevelFilter::Debug,
+      Some(Level::Trace) => log::LevelFilter::Trace,
+    }
+  }
+}
+
+fn parse_level(level: &str) -> Result<Option<Level>, String> {
+  match level.to_ascii_lowercase().as_str() {
+    "off" => Ok(None),
+    "crit" | "critical" => Ok(Some(Level::Critical)),
+    "error" => Ok(Some(Level::Error)),
+    "warn" | "warning" => Ok(Some(Level::Warning)),
+    "info" => Ok(Some(Level::Info)),
+    "debug" => Ok(Some(Level::Debug)),
+    "trace" => Ok(Some(Level::Trace)),
+    _ => Err(format!("{:?} is not a log level", level)),
+  }
+}
+
+/// Sets up `LOG` and routes the records of dependencies using the `log`
+/// crate, e.g. fuser, through it. Must run before `LOG` is first used.
+pub(crate) fn init(filter: LogFilter, format: LogFormat, target: LogTarget) -> Result<(), Error> {
+  let base = Arc::new(build(format, &target)?);
+  let filter = Arc::new(filter);
+  let max_level = filter.max_level();
+  *CONFIGURED.lock().unwrap() = Some(Logger::root(
+    Filtered { filter: filter.clone(), drain: base.clone() },
+    o!(),
+  ));
+  let bridge = Bridge { filter, logger: Logger::root(base, o!()) };
+  if log::set_boxed_logger(Box::new(bridge)).is_ok() {
+    log::set_max_level(max_level);
+  }
+  Ok(())
+}
+
+/// The logger behind `LOG`: the one `init` built, or terminal output
+/// filtered by `RUST_LOG` when it did not run, e.g. in tests.
+pub(crate) fn root() -> Logger {
+  if let Some(logger) = CONFIGURED.lock().unwrap().take() {
+    return logger;
+  }
+  let filter = std::env::var("RUST_LOG")
+    .ok()
+    .map(|spec| LogFilter::parse(&spec))
+    .unwrap_or_else(|| LogFilter::parse("info"));
+  let drain = build(LogFormat::Term, &LogTarget::Stderr).expect("stderr needs no setup");
+  Logger::root(Filtered { filter: Arc::new(filter), drain }, o!())
+}
+
+type BoxedDrain = Box<dyn Drain<Ok = (), Err = Never> + Send + Sync + std::panic::RefUnwindSafe + std::panic::UnwindSafe>;
+
+/// Writes out every record logged so far. Records logged afterwards are
+/// dropped, call it right before the process exits.
+pub(crate) fn flush() {
+  WRITERS.lock().unwrap().clear();
+}
+
+fn build(format: LogFormat, target: &LogTarget) -> Result<BoxedDrain, Error> {
+  let drain = match (format, target) {
+    (LogFormat::Term, LogTarget::Stderr) => {
+      let decorator = slog_term::TermDecorator::new().stderr().build();
+      background(slog_term::CompactFormat::new(decorator).build().fuse())
+    }
+    (LogFormat::Term, LogTarget::File(path)) => {
+      let decorator = slog_term::PlainSyncDecorator::new(append(path)?);
+      background(slog_term::FullFormat::new(decorator).build().fuse())
+    }
+    (LogFormat::Json, LogTarget::Stderr) => background(JsonDrain(Mutex::new(std::io::stderr()))),
+    (LogFormat::Json, LogTarget::File(path)) => background(JsonDrain(Mutex::new(append(path)?))),
+    (format, LogTarget::Syslog) => background(SyslogDrain::open(format)),
+  };
+  Ok(drain)
+}
+
+/// Moves the writing of `drain` to its own thread, so slow output never
+/// stalls a FUSE callback.
+fn background<D: Drain<Ok = (), Err = Never> + Send + 'static>(drain: D) -> BoxedDrain {
+  let (drain, writer) = Async::new(drain).build_with_guard();
+  WRITERS.lock().unwrap().push(writer);
+  Box::new(drain.ignore_res())
+}
+
+fn append(path: &str) -> Result<File, Error> {
+  OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Drops the records `filter` does not let through, by the module that
+/// logged them.
+struct Filtered<D> {
+  filter: Arc<LogFilter>,
+  drain: D,
+}
+
+impl<D: Drain<Ok = (), Err = Never>> Drain for Filtered<D> {
+  type Ok = ();
+  type Err = Never;
+
+  fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+    if self.filter.enabled(record.module(), record.level()) {
+      self.drain.log(record, values)?;
+    }
+    Ok(())
+  }
+}
+
+/// Forwards `log` records to slog. They are filtered by their target, the
+/// module they came from, which slog no longer sees.
+struct Bridge {
+  filter: Arc<LogFilter>,
+  logger: Logger,
+}
+
+impl log::Log for Bridge {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    let level = match metadata.level() {
+      log::Level::Error => Level::Error,
+      log::Level::Warn => Level::Warning,
+      log::Level::Info => Level::Info,
+      log::Level::Debug => Level::Debug,
+      log::Level::Trace => Level::Trace,
+    };
+    self.filter.enabled(metadata.target(), level)
+  }
+
+  fn log(&self, record: &log::Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let target = record.target();
+    match record.level() {
+      log::Level::Error => slog::error!(self.logger, "{}", record.args(); "target" => target),
+      log::Level::Warn => slog::warn!(self.logger, "{}", record.args(); "target" => target),
+      log::Level::Info => slog::info!(self.logger, "{}", record.args(); "target" => target),
+      log::Level::Debug => slog::debug!(self.logger, "{}", record.args(); "target" => target),
+      log::Level::Trace => slog::trace!(self.logger, "{}", record.args(); "target" => target),
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+/// Collects the key-value pairs of a record as strings.
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Serializer for Fields {
+  fn emit_arguments(&mut self, key: Key, val: &Arguments) -> slog::Result {
+    self.0.insert(key.to_string(), Value::String(val.to_string()));
+    Ok(())
+  }
+}
+
+fn fields(record: &Record, values: &OwnedKVList) -> Map<String, Value> {
+  let mut fields = Fields::default();
+  let _ = values.serialize(record, &mut fields);
+  let _ = record.kv().serialize(record, &mut fields);
+  fields.0
+}
+
+fn json_line(record: &Record, values: &OwnedKVList) -> String {
+  let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+  let mut line = json!({
+    "ts_ms": ts,
+    "level": record.level().as_str(),
+    "module": record.module(),
+    "msg": record.msg().to_string(),
+  });
+  for (key, value) in fields(record, values) {
+    line[key] = value;
+  }
+  line.to_string()
+}
+
+struct JsonDrain<W: Write>(Mutex<W>);
+
+impl<W: Write> Drain for JsonDrain<W> {
+  type Ok = ();
+  type Err = Never;
+
+  fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+    let mut line = json_line(record, values);
+    line.push('\n');
+    // Nowhere left to report a failed write to
+    let _ = self.0.lock().unwrap().write_all(line.as_bytes());
+    Ok(())
+  }
+}
+
+/// Sends records to the local syslog daemon, under the daemon facility.
+struct SyslogDrain {
+  format: LogFormat,
+}
+
+impl SyslogDrain {
+  fn open(format: LogFormat) -> Self {
+    // SAFETY: the identity is a static NUL terminated string, as openlog
+    // keeps the pointer
+    unsafe { libc::openlog(c"fs-proxy".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+    SyslogDrain { format }
+  }
+}
+
+impl Drain for SyslogDrain {
+  type Ok = ();
+  type Err = Never;
+
+  fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+    let message = match self.format {
+      LogFormat::Json => json_line(record, values),
+      LogFormat::Term => {
+        let mut message = format!("{}: {}", record.module(), record.msg());
+        for (key, value) in fields(record, values) {
+          message.push_str(&format!(", {}: {}", key, value.as_str().unwrap_or_default()));
+        }
+        message
+      }
+    };
+    let priority = match record.level() {
+      Level::Critical => libc::LOG_CRIT,
+      Level::Error => libc::LOG_ERR,
+      Level::Warning => libc::LOG_WARNING,
+      Level::Info => libc::LOG_INFO,
+      Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    };
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    // SAFETY: both strings are NUL terminated and the message is passed as
+    // an argument, never as the format
+    unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_filter() {
+    let filter = LogFilter::parse("warn,fs_proxy::reload=debug,fuser=off");
+    assert!(filter.enabled("fs_proxy", Level::Warning));
+    assert!(!filter.enabled("fs_proxy", Level::Info));
+    assert!(filter.enabled("fs_proxy::reload", Level::Debug));
+    assert!(!filter.enabled("fs_proxy::reload", Level::Trace));
+    assert!(!filter.enabled("fs_proxy::reloaded", Level::Debug));
+    assert!(!filter.enabled("fuser::request", Level::Error));
+    assert_eq!(filter.max_level(), log::LevelFilter::Debug);
+
+    let filter = LogFilter::parse("fs_proxy");
+    assert!(filter.enabled("fs_proxy::inode", Level::Trace));
+    assert!(!filter.enabled("fuser", Level::Error));
+
+    let filter = LogFilter::parse("info,fs_proxy=loud,=debug,fuser=warn");
+    assert_eq!(filter.invalid(), ["fs_proxy=loud", "=debug"]);
+    assert!(filter.enabled("fs_proxy", Level::Info));
+    assert!(!filter.enabled("fuser", Level::Info));
+    assert_eq!(LogFilter::parse(""), LogFilter::parse("info"));
+  }
+}
@@ -1,67 +1,231 @@
+mod access_log;
+mod archive;
 mod args;
+mod control;
+mod daemon;
 mod mapping;
+mod metrics;
+mod metadata;
 mod inode;
+mod logging;
+mod overlay;
+mod reload;
+mod shutdown;
+mod verify;
+mod xattr;
 
-use std::cell::RefCell;
-use std::cmp::min;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use clap::{Parser};
-use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request, ReplyOpen, ReplyEmpty};
-use libc;
-use std::ffi::OsStr;
-use std::io::{Error, SeekFrom};
+use fuser::{consts, FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, Request, ReplyOpen, ReplyEmpty, ReplyWrite, ReplyXattr, ReplyCreate, Session, TimeOrNow};
+use std::ffi::{CString, OsStr};
+use std::cmp::min;
+use std::io::{Error, ErrorKind};
 use std::ops::{Add, Deref};
-use std::os::unix::fs::MetadataExt;
-use std::process::exit;
-use std::rc::Rc;
-use std::sync::{Arc};
-use std::time::{Duration, UNIX_EPOCH};
+use std::fs::Permissions;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use libc::c_int;
+use std::sync::{mpsc, Arc, Mutex as SyncMutex, RwLock as SyncRwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::fmt::{Display, Formatter};
-use slog::{error, debug, info, Logger, o, Drain};
-use slog_term;
-use slog_async::{Async};
+use slog::{error, debug, info, warn, Logger};
 use crate::args::Args;
-use tokio;
-use tokio::io::{AsyncSeekExt, AsyncReadExt};
-use tokio::runtime::{Runtime};
-use tokio::sync::RwLock;
-use tokio::fs::File;
+use crate::daemon::Readiness;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::{Mutex, RwLock};
+use tokio::fs::{File, OpenOptions};
+use crate::access_log::{Access, AccessLog};
+use crate::archive::{ArchiveMember, MemberReader};
 use crate::inode::{INode, INodeOps, INodeTable};
-use crate::mapping::Path;
+use crate::logging::LogTarget;
+use crate::mapping::{Metadata, Path};
+use crate::metadata::MetadataCache;
+use crate::metrics::{Op, METRICS};
+use crate::overlay::Overlay;
+use crate::reload::MappingSource;
+use crate::shutdown::{Reason, Shutdown};
+use crate::verify::Verifier;
 use lazy_static::lazy_static;
 
-const TTL: Duration = Duration::from_secs(0); // 1 second
+/// TTLs used with `--immutable` unless given explicitly.
+const IMMUTABLE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 lazy_static! {
-  static ref LOG: Logger = {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = Async::new(drain).build().fuse();
-    Logger::root(drain, o!())
-  };
+  static ref LOG: Logger = logging::root();
+}
+
+struct FileHandle {
+  ino: u64,
+  target: String,
+  /// Only ever accessed with positional I/O on the blocking pool, so
+  /// concurrent reads and writes on one handle need no lock.
+  file: std::fs::File,
+  /// Set when the handle reads an archive member stored inside `target`.
+  member: Option<Arc<SyncMutex<MemberReader>>>,
+  access: Access,
+}
+
+impl FileHandle {
+  /// Reads up to `size` bytes at `offset`. Blocks, call it from the blocking
+  /// pool.
+  fn read_at(&self, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+    let buf = match &self.member {
+      Some(member) => member.lock().unwrap().read_at(offset, size)?,
+      None => read_full_at(&self.file, offset, size)?,
+    };
+    self.access.add_bytes_read(buf.len());
+    Ok(buf)
+  }
 }
 
+/// Reads up to `size` bytes at `offset`, carrying on after partial reads
+/// until the buffer is full or the file ends. A file that shrank since it was
+/// opened gives a short read, never an error.
+pub(crate) fn read_full_at(file: &std::fs::File, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+  // pread fails with EINVAL when offset + size overflows an off_t, what lies
+  // beyond can only be EOF anyway
+  let size = min(size as u64, (i64::MAX as u64).saturating_sub(offset)) as usize;
+  let mut buf = vec![0; size];
+  let mut filled = 0;
+  while filled < size {
+    match file.read_at(&mut buf[filled..], offset + filled as u64) {
+      Ok(0) => break,
+      Ok(read) => filled += read,
+      Err(err) if err.kind() == ErrorKind::Interrupted => {}
+      Err(err) => return Err(err),
+    }
+  }
+  buf.truncate(filled);
+  Ok(buf)
+}
+
+/// One entry of a directory listing, as it was when the directory was
+/// opened.
+struct DirEntry {
+  ino: u64,
+  kind: FileType,
+  name: String,
+  inode: Arc<SyncRwLock<INode>>,
+}
 
-const HELLO_DIR_ATTR: FileAttr = FileAttr {
-  ino: 1,
-  size: 0,
-  blocks: 0,
-  atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-  mtime: UNIX_EPOCH,
-  ctime: UNIX_EPOCH,
-  crtime: UNIX_EPOCH,
-  kind: FileType::Directory,
-  perm: 0o755,
-  nlink: 2,
-  uid: 501,
-  gid: 20,
-  rdev: 0,
-  flags: 0,
-  blksize: 512,
-};
+/// Snapshot taken by opendir and paged through by readdir.
+struct DirHandle {
+  ino: u64,
+  entries: Vec<DirEntry>,
+}
+
+/// Attributes of entries with no blob to take them from, folders and
+/// symlinks, wherever the mapping does not override them.
+#[derive(Clone, Copy)]
+struct AttrDefaults {
+  uid: u32,
+  gid: u32,
+  dir_mode: u16,
+  /// When the filesystem was mounted.
+  time: SystemTime,
+}
+
+impl AttrDefaults {
+  fn from_args(args: &Args) -> Self {
+    // SAFETY: getuid and getgid cannot fail
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    Self {
+      uid: args.uid.unwrap_or(uid),
+      gid: args.gid.unwrap_or(gid),
+      dir_mode: args.dir_mode,
+      time: SystemTime::now(),
+    }
+  }
+
+  /// A folder links to itself, its parent and back from each subfolder.
+  fn folder_attr(&self, inode: &INode) -> FileAttr {
+    let (meta, subfolders) = match inode {
+      INode::Folder { meta, entries, .. } => (
+        meta.clone(),
+        entries.values().filter(|entry| entry.read().unwrap().is_folder()).count(),
+      ),
+      _ => (Metadata::default(), 0),
+    };
+    let attr = FileAttr {
+      ino: inode.get_ino(),
+      size: 0,
+      blocks: 0,
+      atime: self.time,
+      mtime: self.time,
+      ctime: self.time,
+      crtime: self.time,
+      kind: FileType::Directory,
+      perm: self.dir_mode,
+      nlink: 2 + subfolders as u32,
+      uid: self.uid,
+      gid: self.gid,
+      rdev: 0,
+      flags: 0,
+      blksize: 512,
+    };
+    apply_metadata(attr, &meta)
+  }
+
+  /// Symlinks have no blob behind them, the size is the length of the target
+  /// like on any other filesystem.
+  fn symlink_attr(&self, inode: &INode, target: &str) -> FileAttr {
+    FileAttr {
+      size: target.len() as u64,
+      kind: FileType::Symlink,
+      perm: 0o777,
+      nlink: 1,
+      ..self.folder_attr(inode)
+    }
+  }
+}
+
+/// How long the kernel may keep what we reply with.
+#[derive(Clone, Copy)]
+struct CachePolicy {
+  entry_ttl: Duration,
+  attr_ttl: Duration,
+  /// Open flags, `FOPEN_KEEP_CACHE` keeps the page cache of a file across
+  /// opens instead of dropping it every time.
+  open_flags: u32,
+}
+
+impl CachePolicy {
+  fn from_args(args: &Args) -> Self {
+    let default_ttl = if args.immutable { IMMUTABLE_TTL } else { Duration::ZERO };
+    Self {
+      entry_ttl: args.entry_ttl.unwrap_or(default_ttl),
+      attr_ttl: args.attr_ttl.unwrap_or(default_ttl),
+      open_flags: if args.immutable { consts::FOPEN_KEEP_CACHE } else { 0 },
+    }
+  }
+
+  /// TTL of replies carrying an entry along with its attributes. fuser sends
+  /// the same TTL for both, the shorter one keeps either from going stale.
+  fn entry_attr_ttl(&self) -> Duration {
+    min(self.entry_ttl, self.attr_ttl)
+  }
+}
 
 struct Inner {
-  file_handles: BTreeMap<u64, Arc<RwLock<File>>>,
+  source: MappingSource,
+  attached: BTreeMap<String, String>,
+  blob_root: Option<String>,
+  overlay: Option<Overlay>,
+  /// Set with `--verify`, checks blobs on open.
+  verifier: Option<Arc<Verifier>>,
+  inode_table: INodeTable,
+  /// Blob metadata by inode, emptied on reload.
+  metadata: Arc<MetadataCache>,
+  /// Set with `--access-log`, records every open and release.
+  access_log: Option<Arc<AccessLog>>,
+  /// Held for the whole of a reload, see `reload::reload`.
+  reloading: Arc<Mutex<()>>,
+  /// Files a reload pointed at another blob under the same inode number. The
+  /// kernel may still hold pages of the old blob, their next open drops them.
+  stale: HashSet<u64>,
+  file_handles: BTreeMap<u64, Arc<FileHandle>>,
+  dir_handles: BTreeMap<u64, Arc<DirHandle>>,
   counter: u64,
+  /// Set once shutdown started, opens are refused from then on.
+  closing: bool,
 }
 
 impl Inner {
@@ -69,30 +233,84 @@ impl Inner {
     self.counter += 1;
     self.counter
   }
+
+  /// Takes `name` out of the folder `parent` and drops the cached metadata
+  /// of everything below it.
+  fn forget(&mut self, parent: u64, name: &str) -> Option<Arc<SyncRwLock<INode>>> {
+    let removed = self.inode_table.remove(parent, name)?;
+    for (_, inode) in removed.list_with_paths() {
+      self.metadata.invalidate(inode.read().unwrap().get_ino());
+    }
+    Some(removed)
+  }
 }
 
 struct MappingFS {
-  runtime: Runtime,
-  inode_table: INodeTable,
+  /// The runtime itself stays with `main`. An unmount from outside ends the
+  /// session before shutdown drained the handles on it.
+  runtime: Handle,
+  defaults: AttrDefaults,
+  policy: CachePolicy,
   inner: Arc<RwLock<Inner>>,
+  shutdown: Arc<Shutdown>,
+  /// Told when the kernel completed the handshake, see `initialized`.
+  initialized: Option<mpsc::Sender<()>>,
 }
 
 impl MappingFS {
-  fn new(runtime: Runtime, mapping: Path) -> Self {
-    let root: Rc<RefCell<INode>> = Rc::new(RefCell::new(mapping.into()));
+  fn new(
+    runtime: Handle,
+    args: &Args,
+    source: MappingSource,
+    overlay: Option<Overlay>,
+    access_log: Option<AccessLog>,
+    mapping: Path,
+  ) -> Self {
+    let root: Arc<SyncRwLock<INode>> = Arc::new(SyncRwLock::new(mapping.into()));
     Self {
+      shutdown: Arc::new(Shutdown::new(&runtime)),
       runtime,
-      inode_table: INodeTable::from(root),
+      defaults: AttrDefaults::from_args(args),
+      policy: CachePolicy::from_args(args),
       inner: Arc::new(RwLock::new(Inner {
+        source,
+        attached: Default::default(),
+        blob_root: args.blob_root.clone(),
+        overlay,
+        verifier: args.verify.then(|| Arc::new(Verifier::new(args.verify_ttl))),
+        inode_table: INodeTable::from(root),
+        metadata: Default::default(),
+        access_log: access_log.map(Arc::new),
+        reloading: Default::default(),
+        stale: Default::default(),
         file_handles: Default::default(),
+        dir_handles: Default::default(),
         counter: 0,
+        closing: false,
       })),
+      initialized: None,
     }
   }
 
-  async fn getattr(ino: u64, name: &String) -> Result<FileAttr, Error> {
-    let file = File::open(name).await?;
-    let metadata = file.metadata().await?;
+  /// Receives once the mount serves requests. Fails instead when the session
+  /// ends before that.
+  fn initialized(&mut self) -> mpsc::Receiver<()> {
+    let (sender, receiver) = mpsc::channel();
+    self.initialized = Some(sender);
+    receiver
+  }
+
+  /// Attributes of the blob `name` under the overrides in `meta`. For an
+  /// archive member the size is the member's, everything else comes from the
+  /// archive blob.
+  async fn getattr(
+    cache: &MetadataCache,
+    ino: u64,
+    name: &str,
+    member: Option<&ArchiveMember>,
+    meta: &Metadata,
+  ) -> Result<FileAttr, Error> {
+    let metadata = cache.get(ino, name).await?;
     let kind = if metadata.is_dir() {
       FileType::Directory
     } else if metadata.is_file() {
@@ -103,10 +321,15 @@ impl MappingFS {
       FileType::NamedPipe
     };
 
+    let (size, blocks) = match member {
+      Some(member) => (member.size, member.size / 512 + u64::from(member.size % 512 != 0)),
+      None => (metadata.size(), metadata.blocks()),
+    };
+
     let attr = FileAttr {
       ino,
-      size: metadata.size(),
-      blocks: metadata.blocks(),
+      size,
+      blocks,
       atime: UNIX_EPOCH.add(Duration::from_secs(metadata.atime() as u64)),
       mtime: UNIX_EPOCH.add(Duration::from_secs(metadata.mtime() as u64)),
       ctime: UNIX_EPOCH.add(Duration::from_secs(metadata.ctime() as u64)),
@@ -120,124 +343,427 @@ impl MappingFS {
       blksize: metadata.blksize() as u32,
       flags: 0,
     };
-    Ok(attr)
+    Ok(apply_metadata(attr, meta))
+  }
+
+  /// Attributes of any kind of inode, as lookup would give them.
+  async fn attr_of(cache: &MetadataCache, defaults: &AttrDefaults, inode: &Arc<SyncRwLock<INode>>) -> Result<FileAttr, Error> {
+    let (ino, target, member, meta) = match inode.read().unwrap().deref() {
+      INode::File { ino, target, member, meta, .. } => (*ino, target.clone(), member.clone(), meta.clone()),
+      folder @ INode::Folder { .. } => return Ok(defaults.folder_attr(folder)),
+      symlink @ INode::Symlink { target, .. } => return Ok(defaults.symlink_attr(symlink, target)),
+    };
+    Self::getattr(cache, ino, &target, member.as_ref(), &meta).await
+  }
+
+  /// Shared by unlink and rmdir: drops `name` from the upper layer, hides the
+  /// lower entry behind a whiteout and takes it out of the inode table.
+  fn remove_entry(&mut self, parent: u64, name: &OsStr, folder: bool, reply: ReplyEmpty) {
+    debug!(LOG, "remove(parent={}, name={:?}, folder={})", parent, name, folder);
+    let (overlay, path, name) = match self.upper_entry(parent, name) {
+      Ok(entry) => entry,
+      Err(errno) => {
+        reply.error(errno);
+        return;
+      }
+    };
+    let Some(inode) = self.inner.blocking_read().inode_table.lookup(parent, name.clone()) else {
+      reply.error(libc::ENOENT);
+      return;
+    };
+    match (folder, inode.read().unwrap().is_folder()) {
+      (true, false) => {
+        reply.error(libc::ENOTDIR);
+        return;
+      }
+      (false, true) => {
+        reply.error(libc::EISDIR);
+        return;
+      }
+      _ => {}
+    }
+    if folder && !inode.list_current().is_empty() {
+      reply.error(libc::ENOTEMPTY);
+      return;
+    }
+
+    let send_inner = self.inner.clone();
+    self.runtime.spawn(async move {
+      if let Err(err) = overlay.remove(&path).await {
+        error!(LOG, "Failed to remove {}: {}", path, err);
+        reply.error(errno(&err));
+        return;
+      }
+      send_inner.write().await.forget(parent, &name);
+      reply.ok();
+    });
+  }
+
+  /// The upper layer plus the virtual path and name of `name` in the folder
+  /// `parent`, or the errno to reply with.
+  fn upper_entry(&self, parent: u64, name: &OsStr) -> Result<(Overlay, String, String), i32> {
+    let inner = self.inner.blocking_read();
+    let overlay = inner.overlay.clone().ok_or(libc::EROFS)?;
+    let name = name.to_str().ok_or(libc::EINVAL)?;
+    if name == "." || name == ".." {
+      return Err(libc::EINVAL);
+    }
+    if overlay::is_reserved(name) {
+      return Err(libc::EPERM);
+    }
+    let parent_path = inner.inode_table.path_of(parent).ok_or(libc::ENOENT)?;
+    Ok((overlay, format!("{}/{}", parent_path.trim_end_matches('/'), name), name.to_string()))
+  }
+
+  /// Moves the file behind `inode` to the upper layer on its first write and
+  /// points the inode at the copy. The copy takes the mode from the mapping,
+  /// the time overrides are dropped so that writes show up.
+  async fn copy_up(overlay: &Overlay, inode: &Arc<SyncRwLock<INode>>, path: &str) -> Result<String, Error> {
+    let _guard = overlay.lock().await;
+    let (current, member, mode) = match inode.read().unwrap().deref() {
+      INode::File { target, member, meta, .. } => (target.clone(), member.clone(), meta.mode),
+      INode::Folder { .. } => return Err(Error::from_raw_os_error(libc::EISDIR)),
+      INode::Symlink { .. } => return Err(Error::from_raw_os_error(libc::ELOOP)),
+    };
+    let copied = !overlay.is_upper(&current);
+    let target = overlay.copy_up(path, &current, member.as_ref()).await?;
+    if copied {
+      if let Some(mode) = mode {
+        tokio::fs::set_permissions(&target, Permissions::from_mode(mode & 0o7777)).await?;
+      }
+    }
+    if let INode::File { target: t, member, sha256, meta, .. } = &mut *inode.write().unwrap() {
+      *t = target.clone();
+      *member = None;
+      *sha256 = None;
+      *meta = meta.copied_up();
+    }
+    Ok(target)
+  }
+}
+
+/// Writes `data` at `offset` of `handle` on the blocking pool. The cached
+/// metadata of the file is dropped once the write is done, a stat racing it
+/// would otherwise cache the size from before.
+async fn write_at(handle: Arc<FileHandle>, cache: &MetadataCache, offset: u64, data: Vec<u8>) -> Result<(), Error> {
+  let ino = handle.ino;
+  let written = tokio::task::spawn_blocking(move || handle.file.write_all_at(&data, offset))
+    .await
+    .map_err(Error::from)
+    .and_then(|written| written);
+  cache.invalidate(ino);
+  written
+}
+
+fn errno(err: &Error) -> i32 {
+  err.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// A zero `size` asks how large the value is, any other the value itself if
+/// it fits.
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+  if size == 0 {
+    reply.size(value.len() as u32);
+  } else if value.len() > size as usize {
+    reply.error(libc::ERANGE);
+  } else {
+    reply.data(value);
   }
 }
 
-fn make_folder_attr(inode: &INode) -> FileAttr {
-  FileAttr {
-    ino: inode.get_ino(),
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
+/// Lays the overrides from the mapping over attributes read from the blob.
+fn apply_metadata(mut attr: FileAttr, meta: &Metadata) -> FileAttr {
+  if let Some(mode) = meta.mode {
+    attr.perm = (mode & 0o7777) as u16;
+  }
+  if let Some(uid) = meta.uid {
+    attr.uid = uid;
+  }
+  if let Some(gid) = meta.gid {
+    attr.gid = gid;
+  }
+  if let Some(mtime) = meta.mtime {
+    attr.mtime = epoch_time(mtime);
+  }
+  if let Some(atime) = meta.atime {
+    attr.atime = epoch_time(atime);
+  }
+  if let Some(ctime) = meta.ctime {
+    attr.ctime = epoch_time(ctime);
+  }
+  attr
+}
+
+fn epoch_time(secs: i64) -> SystemTime {
+  if secs >= 0 {
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+  } else {
+    UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
   }
 }
 
 impl Filesystem for MappingFS {
+  fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+    // Listings then carry the attributes of their entries, instead of the
+    // kernel looking up every entry on its own
+    if let Err(unsupported) = config.add_capabilities(consts::FUSE_DO_READDIRPLUS) {
+      warn!(LOG, "Kernel does not support readdirplus (capabilities {:#x})", unsupported);
+    }
+    if let Some(initialized) = self.initialized.take() {
+      let _ = initialized.send(());
+    }
+    Ok(())
+  }
+
+  fn destroy(&mut self) {
+    self.shutdown.request(Reason::Unmounted);
+  }
+
   fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
     debug!(LOG, "lookup called with parent={}, name={:?}", parent, name);
+    let timer = METRICS.start(Op::Lookup);
     let Some(filename) = name.to_str() else {
-      reply.error(libc::ENOENT);
+      reply.error(timer.fail(libc::ENOENT));
       return;
     };
-    let res = self.inode_table.lookup(parent, filename.to_string());
+    let (res, cache) = {
+      let inner = self.inner.blocking_read();
+      (inner.inode_table.lookup(parent, filename.to_string()), inner.metadata.clone())
+    };
     match res {
       Some(inode) => {
-        match inode.borrow().deref() {
-          INode::File { target, .. } => {
+        let inode = inode.read().unwrap();
+        match inode.deref() {
+          INode::File { ino, target, member, meta, .. } => {
             debug!(LOG, "lookup: found file {} -> {}", filename, target);
             let binding = target.clone();
-            let ino = inode.borrow().get_ino();
+            let member = member.clone();
+            let meta = meta.clone();
+            let ino = *ino;
+            let ttl = self.policy.entry_attr_ttl();
             self.runtime.spawn(async move {
-              match Self::getattr(ino, &binding).await {
+              match Self::getattr(&cache, ino, &binding, member.as_ref(), &meta).await {
                 Ok(attr) => {
                   debug!(LOG, "lookup: got attr for {}: {:?}", binding, attr);
-                  reply.entry(&TTL, &attr, 0);
+                  reply.entry(&ttl, &attr, 0);
                 }
                 Err(err) => {
                   error!(LOG, "Failed to get attr for {}: {}", binding, err);
-                  reply.error(libc::EIO)
+                  reply.error(timer.fail(libc::EIO))
                 }
               }
             });
           }
           INode::Folder { .. } => {
             debug!(LOG, "lookup: found folder {}", filename);
-            let attr = make_folder_attr(&*inode.borrow());
-            reply.entry(&TTL, &attr, 0);
+            let attr = self.defaults.folder_attr(&inode);
+            reply.entry(&self.policy.entry_attr_ttl(), &attr, 0);
+          }
+          INode::Symlink { target, .. } => {
+            debug!(LOG, "lookup: found symlink {} -> {}", filename, target);
+            reply.entry(&self.policy.entry_attr_ttl(), &self.defaults.symlink_attr(&inode, target), 0);
           }
         }
       }
       None => {
-        reply.error(libc::ENOENT);
+        reply.error(timer.fail(libc::ENOENT));
       }
     }
   }
 
   fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-    let Some(inode) = self.inode_table.get_by_ino(ino) else {
-      reply.error(libc::ENOENT);
+    let timer = METRICS.start(Op::Getattr);
+    let (inode, cache) = {
+      let inner = self.inner.blocking_read();
+      (inner.inode_table.get_by_ino(ino), inner.metadata.clone())
+    };
+    let Some(inode) = inode else {
+      reply.error(timer.fail(libc::ENOENT));
       return;
     };
 
-    match inode.borrow().deref() {
-      INode::File { target, .. } => {
+    let inode = inode.read().unwrap();
+    match inode.deref() {
+      INode::File { target, member, meta, .. } => {
         let bind = target.clone();
+        let member = member.clone();
+        let meta = meta.clone();
+        let ttl = self.policy.attr_ttl;
         self.runtime.spawn(async move {
-          match Self::getattr(ino, &bind).await {
+          match Self::getattr(&cache, ino, &bind, member.as_ref(), &meta).await {
             Ok(attr) => {
-              reply.attr(&TTL, &attr);
+              reply.attr(&ttl, &attr);
             }
             Err(err) => {
               error!(LOG, "Failed to get attr for {}: {}", bind, err);
-              reply.error(libc::EIO)
+              reply.error(timer.fail(libc::EIO))
             }
           }
         });
       }
       INode::Folder { .. } => {
-        reply.attr(&TTL, &HELLO_DIR_ATTR);
+        reply.attr(&self.policy.attr_ttl, &self.defaults.folder_attr(&inode));
+      }
+      INode::Symlink { target, .. } => {
+        reply.attr(&self.policy.attr_ttl, &self.defaults.symlink_attr(&inode, target));
       }
     };
   }
 
-  fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-    let Some(inode) = self.inode_table.get_by_ino(ino) else {
+  fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+    let Some(inode) = self.inner.blocking_read().inode_table.get_by_ino(ino) else {
       reply.error(libc::ENOENT);
       return;
     };
+    match inode.read().unwrap().deref() {
+      INode::Symlink { target, .. } => reply.data(target.as_bytes()),
+      _ => reply.error(libc::EINVAL),
+    };
+  }
 
-    let inode_borrow = inode.borrow();
-    let INode::File { target, .. } = inode_borrow.deref() else {
-      reply.error(libc::ENFILE);
+  fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+    debug!(LOG, "getxattr(ino={}, name={:?}, size={})", ino, name, size);
+    let Some(inode) = self.inner.blocking_read().inode_table.get_by_ino(ino) else {
+      reply.error(libc::ENOENT);
       return;
     };
+    let value = name.to_str().and_then(|name| xattr::get(&inode.read().unwrap(), name));
+    match value {
+      Some(value) => reply_xattr(value.as_bytes(), size, reply),
+      None => reply.error(libc::ENODATA),
+    }
+  }
 
-    let send_inner = self.inner.clone();
+  fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+    debug!(LOG, "listxattr(ino={}, size={})", ino, size);
+    let Some(inode) = self.inner.blocking_read().inode_table.get_by_ino(ino) else {
+      reply.error(libc::ENOENT);
+      return;
+    };
+    let names = xattr::list(&inode.read().unwrap());
+    reply_xattr(&names, size, reply);
+  }
+
+  fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+    let timer = METRICS.start(Op::Open);
+    let (path, access_log) = {
+      let inner = self.inner.blocking_read();
+      (inner.inode_table.path_of(ino).unwrap_or_default(), inner.access_log.clone())
+    };
+    let access = Access::new(path, req.uid(), req.gid(), req.pid());
+    let failed = |target: &str, errno: i32| {
+      if let Some(access_log) = &access_log {
+        access_log.failed(ino, target, flags, &access, errno);
+      }
+      timer.fail(errno)
+    };
+    if self.inner.blocking_read().closing {
+      reply.error(failed("", libc::ESHUTDOWN));
+      return;
+    }
+    let Some(inode) = self.inner.blocking_read().inode_table.get_by_ino(ino) else {
+      reply.error(failed("", libc::ENOENT));
+      return;
+    };
+
+    let inode_borrow = inode.read().unwrap();
+    let INode::File { target, member, sha256, .. } = inode_borrow.deref() else {
+      drop(inode_borrow);
+      reply.error(failed("", libc::ENFILE));
+      return;
+    };
     let binding = target.clone();
+    let member = member.clone();
+    let sha256 = sha256.clone();
+    drop(inode_borrow);
+
+    let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+    let upper = if writable {
+      let inner = self.inner.blocking_read();
+      let (Some(overlay), Some(path)) = (inner.overlay.clone(), inner.inode_table.path_of(ino)) else {
+        drop(inner);
+        reply.error(failed(&binding, libc::EROFS));
+        return;
+      };
+      Some((overlay, path))
+    } else {
+      None
+    };
+
+    // Upper files are our own writes, there is no digest to hold them to
+    let verifier = {
+      let inner = self.inner.blocking_read();
+      let upper = matches!(&inner.overlay, Some(overlay) if overlay.is_upper(&binding));
+      inner.verifier.clone().filter(|_| !upper)
+    };
+
+    let send_inner = self.inner.clone();
+    let policy = self.policy;
     self.runtime.spawn(async move {
-      match File::open(&binding).await {
-        Ok(file) => {
-          let arc_file = Arc::new(RwLock::new(file));
+      let opened = match upper {
+        Some((overlay, path)) => async {
+          // The lower blob is checked before it is copied up
+          if let Some(verifier) = &verifier {
+            let file = File::open(&binding).await?.into_std().await;
+            verifier.verify(&file, &binding, sha256.as_deref()).await?;
+          }
+          let target = Self::copy_up(&overlay, &inode, &path).await?;
+          let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .append(flags & libc::O_APPEND != 0)
+            .truncate(flags & libc::O_TRUNC != 0)
+            .open(&target)
+            .await?;
+          Ok::<_, Error>((target, file.into_std().await, None))
+        }.await,
+        // The digest is taken from the very file that is then served, a blob
+        // swapped after the check is never read
+        None => async {
+          let file = File::open(&binding).await?.into_std().await;
+          if let Some(verifier) = &verifier {
+            verifier.verify(&file, &binding, sha256.as_deref()).await?;
+          }
+          let member = match member {
+            Some(member) => Some(Arc::new(SyncMutex::new(MemberReader::new(file.try_clone()?, &binding, member)))),
+            None => None,
+          };
+          Ok((binding.clone(), file, member))
+        }.await,
+      };
+      match opened {
+        Ok((binding, file, member)) => {
+          let handle = Arc::new(FileHandle {
+            ino,
+            target: binding,
+            file,
+            member,
+            access,
+          });
           let mut inner = send_inner.write().await;
+          if writable {
+            inner.metadata.invalidate(ino);
+          }
           let fh = inner.inc_counter();
-          inner.file_handles.insert(fh, arc_file);
-          reply.opened(fh, 0);
+          if let Some(access_log) = &inner.access_log {
+            access_log.opened(fh, ino, &handle.target, flags, &handle.access);
+          }
+          inner.file_handles.insert(fh, handle);
+          let open_flags = if inner.stale.remove(&ino) {
+            policy.open_flags & !consts::FOPEN_KEEP_CACHE
+          } else {
+            policy.open_flags
+          };
+          reply.opened(fh, open_flags);
         }
+        // A blob failing verification has no errno of its own and gets EIO
         Err(err) => {
           info!(LOG, "Failed to open file {}: {}", binding, err);
-          reply.error(libc::EIO);
+          let errno = errno(&err);
+          if let Some(access_log) = &access_log {
+            access_log.failed(ino, &binding, flags, &access, errno);
+          }
+          reply.error(timer.fail(errno));
         }
       }
     });
@@ -255,129 +781,653 @@ impl Filesystem for MappingFS {
     reply: ReplyData,
   ) {
     debug!(LOG, "read(ino={}, offset={})", ino, offset);
+    let timer = METRICS.start(Op::Read);
     let send_inner = self.inner.clone();
     self.runtime.spawn(async move {
-      let inner = send_inner.read().await;
-
-      let Some(arc_file) = inner.file_handles.get(&_fh) else {
+      let Ok(offset) = u64::try_from(offset) else {
+        reply.error(timer.fail(libc::EINVAL));
+        return;
+      };
+      let Some(handle) = send_inner.read().await.file_handles.get(&_fh).cloned() else {
         error!(LOG, "Failed to find file handle {}", _fh);
-        reply.error(libc::EBADF);
+        reply.error(timer.fail(libc::EBADF));
         return;
       };
 
-      let file_clone = arc_file.clone();
-      let mut file = file_clone.write().await;
-
-      let file_size = match file.metadata().await {
-        Ok(metadata) => metadata.len(),
+      let read = tokio::task::spawn_blocking(move || handle.read_at(offset, size as usize))
+        .await
+        .map_err(Error::from)
+        .and_then(|read| read);
+      match read {
+        Ok(buf) => {
+          METRICS.add_bytes_read(buf.len());
+          reply.data(&buf);
+        }
         Err(err) => {
-          error!(LOG, "Failed to get metadata for file handle {}: {}", _fh, err);
-          reply.error(libc::EIO);
-          return;
+          error!(LOG, "Failed to read file handle {}: {}", _fh, err);
+          reply.error(timer.fail(libc::EIO));
         }
-      };
-
-      let read_size = min(size, file_size.saturating_sub(offset as u64) as u32);
-
-      if let Err(err) = file.seek(SeekFrom::Start(offset as u64)).await {
-        error!(LOG, "Failed to seek file handle {}: {}", _fh, err);
-        reply.error(libc::EIO);
-        return;
-      };
-
-      let mut buf = vec![0; read_size as usize];
-      if let Err(err) = file.read_exact(&mut buf).await {
-        error!(LOG, "Failed to read file handle {}: {}", _fh, err);
-        reply.error(libc::EIO);
-        return;
-      };
-
-      reply.data(&buf);
+      }
     });
   }
 
   fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
     debug!(LOG, "release(fh={})", fh);
+    let timer = METRICS.start(Op::Release);
     let send_inner = self.inner.clone();
     self.runtime.spawn(async move {
       let mut inner = send_inner.write().await;
-      let Some(_file) = inner.file_handles.remove(&fh) else {
+      let Some(file) = inner.file_handles.remove(&fh) else {
         error!(LOG, "Failed to find file handle {}", fh);
-        reply.error(libc::ENOENT);
+        reply.error(timer.fail(libc::ENOENT));
         return;
       };
+      if let Some(access_log) = &inner.access_log {
+        access_log.released(fh, file.ino, &file.target, &file.access);
+      }
       reply.ok();
       info!(LOG, "Closing file handle {}", fh);
     });
   }
 
+  fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+    debug!(LOG, "opendir(ino={})", ino);
+    let mut inner = self.inner.blocking_write();
+    if inner.closing {
+      reply.error(libc::ESHUTDOWN);
+      return;
+    }
+    let Some(inode) = inner.inode_table.get_by_ino(ino) else {
+      reply.error(libc::ENOENT);
+      return;
+    };
+    if !inode.read().unwrap().is_folder() {
+      reply.error(libc::ENOTDIR);
+      return;
+    }
+
+    let mut entries = vec![DirEntry {
+      ino,
+      kind: FileType::Directory,
+      name: ".".to_string(),
+      inode: inode.clone(),
+    }];
+    if let Some(parent) = inner.inode_table.lookup(ino, "..".to_string()) {
+      let parent_ino = parent.read().unwrap().get_ino();
+      entries.push(DirEntry {
+        ino: parent_ino,
+        kind: FileType::Directory,
+        name: "..".to_string(),
+        inode: parent,
+      });
+    }
+    for entry in inode.list_current() {
+      let (ino, kind, name) = {
+        let entry = entry.read().unwrap();
+        let kind = match entry.deref() {
+          INode::File { .. } => FileType::RegularFile,
+          INode::Folder { .. } => FileType::Directory,
+          INode::Symlink { .. } => FileType::Symlink,
+        };
+        (entry.get_ino(), kind, entry.get_name().clone())
+      };
+      entries.push(DirEntry { ino, kind, name, inode: entry });
+    }
+
+    let fh = inner.inc_counter();
+    inner.dir_handles.insert(fh, Arc::new(DirHandle { ino, entries }));
+    reply.opened(fh, 0);
+  }
+
   fn readdir(
     &mut self,
     _req: &Request,
     ino: u64,
-    _fh: u64,
+    fh: u64,
     offset: i64,
     mut reply: ReplyDirectory,
   ) {
-    debug!(LOG, "readdir(ino={}, offset={})", ino, offset);
-    let Some(inode) = self.inode_table.get_by_ino(ino) else {
-      debug!(LOG, "readdir(ino={}): libc::ENOENT", ino);
-      reply.error(libc::ENOENT);
+    debug!(LOG, "readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+    let timer = METRICS.start(Op::Readdir);
+    let Some(handle) = self.inner.blocking_read().dir_handles.get(&fh).cloned() else {
+      error!(LOG, "Failed to find directory handle {}", fh);
+      reply.error(timer.fail(libc::EBADF));
       return;
     };
 
-    debug!(LOG, "readdir(ino={}): {:?}", ino, inode.clone().borrow().get_name());
+    // The offset of an entry is its position in the snapshot plus one, so a
+    // reload between two pages neither skips nor repeats anything
+    for (i, entry) in handle.entries.iter().enumerate().skip(offset.max(0) as usize) {
+      debug!(LOG, "readdir(ino={})[{}]: {:?}", handle.ino, i, entry.name);
+      if reply.add(entry.ino, (i + 1) as i64, entry.kind, &entry.name) {
+        break;
+      }
+    }
+    reply.ok();
+  }
 
-    let curr = inode.borrow();
-    let mut files = vec![
-      Rc::new(RefCell::new(INode::Folder {
-        ino: curr.get_ino(),
-        parent: curr.get_parent(),
-        name: ".".to_string(),
-        entries: Default::default(),
-      }))
-    ];
-
-    if let Some(parent) = self.inode_table.get_by_ino(inode.borrow().get_parent()) {
-      let parent_ref = parent.borrow();
-      let parent_folder = Rc::new(RefCell::new(INode::Folder {
-        ino: parent_ref.get_ino(),
-        parent: parent_ref.get_parent(),
-        name: "..".to_string(),
+  fn readdirplus(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+    debug!(LOG, "readdirplus(ino={}, fh={}, offset={})", ino, fh, offset);
+    let timer = METRICS.start(Op::Readdir);
+    let (handle, cache) = {
+      let inner = self.inner.blocking_read();
+      (inner.dir_handles.get(&fh).cloned(), inner.metadata.clone())
+    };
+    let Some(handle) = handle else {
+      error!(LOG, "Failed to find directory handle {}", fh);
+      reply.error(timer.fail(libc::EBADF));
+      return;
+    };
+
+    let (defaults, policy) = (self.defaults, self.policy);
+    self.runtime.spawn(async move {
+      for (i, entry) in handle.entries.iter().enumerate().skip(offset.max(0) as usize) {
+        let (attr, ttl) = match Self::attr_of(&cache, &defaults, &entry.inode).await {
+          Ok(attr) => (attr, policy.entry_attr_ttl()),
+          // The entry still has to be listed, a zero TTL makes the kernel
+          // look it up again and get the error then
+          Err(err) => {
+            error!(LOG, "Failed to get attr for {}: {}", entry.name, err);
+            let attr = FileAttr {
+              kind: entry.kind,
+              ..defaults.folder_attr(&entry.inode.read().unwrap())
+            };
+            (attr, Duration::ZERO)
+          }
+        };
+        if reply.add(entry.ino, (i + 1) as i64, &entry.name, &ttl, &attr, 0) {
+          break;
+        }
+      }
+      reply.ok();
+    });
+  }
+
+  fn releasedir(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+    debug!(LOG, "releasedir(fh={})", fh);
+    if self.inner.blocking_write().dir_handles.remove(&fh).is_none() {
+      error!(LOG, "Failed to find directory handle {}", fh);
+      reply.error(libc::EBADF);
+      return;
+    }
+    reply.ok();
+  }
+
+  fn write(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    data: &[u8],
+    _write_flags: u32,
+    _flags: i32,
+    _lock_owner: Option<u64>,
+    reply: ReplyWrite,
+  ) {
+    debug!(LOG, "write(ino={}, offset={}, size={})", ino, offset, data.len());
+    let send_inner = self.inner.clone();
+    let data = data.to_vec();
+    self.runtime.spawn(async move {
+      let (handle, cache) = {
+        let inner = send_inner.read().await;
+        (inner.file_handles.get(&fh).cloned(), inner.metadata.clone())
+      };
+      let Some(handle) = handle else {
+        error!(LOG, "Failed to find file handle {}", fh);
+        reply.error(libc::EBADF);
+        return;
+      };
+
+      let size = data.len() as u32;
+      match write_at(handle, &cache, offset as u64, data).await {
+        Ok(()) => reply.written(size),
+        Err(err) => {
+          error!(LOG, "Failed to write file handle {}: {}", fh, err);
+          reply.error(errno(&err));
+        }
+      }
+    });
+  }
+
+  fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+    let send_inner = self.inner.clone();
+    self.runtime.spawn(async move {
+      let Some(handle) = send_inner.read().await.file_handles.get(&fh).cloned() else {
+        reply.error(libc::EBADF);
+        return;
+      };
+      let synced = tokio::task::spawn_blocking(move || {
+        if datasync {
+          handle.file.sync_data()
+        } else {
+          handle.file.sync_all()
+        }
+      })
+      .await
+      .map_err(Error::from)
+      .and_then(|synced| synced);
+      match synced {
+        Ok(()) => reply.ok(),
+        Err(err) => reply.error(errno(&err)),
+      }
+    });
+  }
+
+  fn create(
+    &mut self,
+    req: &Request<'_>,
+    parent: u64,
+    name: &OsStr,
+    mode: u32,
+    umask: u32,
+    flags: i32,
+    reply: ReplyCreate,
+  ) {
+    debug!(LOG, "create(parent={}, name={:?})", parent, name);
+    if self.inner.blocking_read().closing {
+      reply.error(libc::ESHUTDOWN);
+      return;
+    }
+    let (overlay, path, name) = match self.upper_entry(parent, name) {
+      Ok(entry) => entry,
+      Err(errno) => {
+        reply.error(errno);
+        return;
+      }
+    };
+
+    let access = Access::new(path.clone(), req.uid(), req.gid(), req.pid());
+    let send_inner = self.inner.clone();
+    let policy = self.policy;
+    self.runtime.spawn(async move {
+      let created = async {
+        let upper = overlay.prepare(&path).await?;
+        let file = OpenOptions::new()
+          .read(true)
+          .write(true)
+          .create(true)
+          .truncate(true)
+          .append(flags & libc::O_APPEND != 0)
+          .mode(mode & !umask & 0o7777)
+          .open(&upper)
+          .await?;
+        Ok::<_, Error>((upper.to_string_lossy().into_owned(), file))
+      }.await;
+      let (target, file) = match created {
+        Ok(created) => created,
+        Err(err) => {
+          error!(LOG, "Failed to create {}: {}", path, err);
+          if let Some(access_log) = &send_inner.read().await.access_log {
+            access_log.failed(0, "", flags, &access, errno(&err));
+          }
+          reply.error(errno(&err));
+          return;
+        }
+      };
+
+      let mut inner = send_inner.write().await;
+      let inode = Arc::new(SyncRwLock::new(INode::File {
+        ino: 0,
+        parent,
+        name,
+        target: target.clone(),
+        member: None,
+        sha256: None,
+        meta: Metadata::default(),
+      }));
+      let Some(ino) = inner.inode_table.insert(parent, inode) else {
+        reply.error(libc::ENOENT);
+        return;
+      };
+      let fh = inner.inc_counter();
+      let handle = Arc::new(FileHandle {
+        ino,
+        target: target.clone(),
+        file: file.into_std().await,
+        member: None,
+        access,
+      });
+      if let Some(access_log) = &inner.access_log {
+        access_log.opened(fh, ino, &handle.target, flags, &handle.access);
+      }
+      inner.file_handles.insert(fh, handle);
+      let cache = inner.metadata.clone();
+      drop(inner);
+
+      match Self::getattr(&cache, ino, &target, None, &Metadata::default()).await {
+        Ok(attr) => reply.created(&policy.entry_attr_ttl(), &attr, 0, fh, 0),
+        Err(err) => {
+          error!(LOG, "Failed to get attr for {}: {}", target, err);
+          reply.error(libc::EIO);
+        }
+      }
+    });
+  }
+
+  fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+    debug!(LOG, "mkdir(parent={}, name={:?})", parent, name);
+    let (overlay, path, name) = match self.upper_entry(parent, name) {
+      Ok(entry) => entry,
+      Err(errno) => {
+        reply.error(errno);
+        return;
+      }
+    };
+
+    let mode = mode & !umask & 0o7777;
+    let (defaults, policy) = (self.defaults, self.policy);
+    let send_inner = self.inner.clone();
+    self.runtime.spawn(async move {
+      if let Err(err) = overlay.mkdir(&path, mode).await {
+        error!(LOG, "Failed to create folder {}: {}", path, err);
+        reply.error(errno(&err));
+        return;
+      }
+
+      let inode = Arc::new(SyncRwLock::new(INode::Folder {
+        ino: 0,
+        parent,
+        name,
         entries: Default::default(),
+        meta: Metadata {
+          mode: Some(mode),
+          ..Metadata::default()
+        },
       }));
-      files.push(parent_folder);
+      if send_inner.write().await.inode_table.insert(parent, inode.clone()).is_none() {
+        reply.error(libc::ENOENT);
+        return;
+      }
+      let attr = defaults.folder_attr(&inode.read().unwrap());
+      reply.entry(&policy.entry_attr_ttl(), &attr, 0);
+    });
+  }
+
+  fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    self.remove_entry(parent, name, false, reply);
+  }
+
+  fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    self.remove_entry(parent, name, true, reply);
+  }
+
+  fn rename(
+    &mut self,
+    _req: &Request<'_>,
+    parent: u64,
+    name: &OsStr,
+    newparent: u64,
+    newname: &OsStr,
+    flags: u32,
+    reply: ReplyEmpty,
+  ) {
+    debug!(LOG, "rename(parent={}, name={:?}, newparent={}, newname={:?})", parent, name, newparent, newname);
+    if flags != 0 {
+      reply.error(libc::EINVAL);
+      return;
     }
+    let (overlay, from, name) = match self.upper_entry(parent, name) {
+      Ok(entry) => entry,
+      Err(errno) => {
+        reply.error(errno);
+        return;
+      }
+    };
+    let (_, to, newname) = match self.upper_entry(newparent, newname) {
+      Ok(entry) => entry,
+      Err(errno) => {
+        reply.error(errno);
+        return;
+      }
+    };
 
-    files.append(&mut inode.list_current());
+    let inner = self.inner.blocking_read();
+    let Some(source) = inner.inode_table.lookup(parent, name.clone()) else {
+      reply.error(libc::ENOENT);
+      return;
+    };
+    let (target, member, link) = match source.read().unwrap().deref() {
+      INode::File { target, member, .. } => (target.clone(), member.clone(), false),
+      INode::Symlink { target, .. } => (target.clone(), None, true),
+      // Folders would need their whole lower subtree copied up, let the
+      // caller fall back to copy and delete like overlayfs does
+      INode::Folder { .. } => {
+        reply.error(libc::EXDEV);
+        return;
+      }
+    };
+    if let Some(existing) = inner.inode_table.lookup(newparent, newname.clone()) {
+      if existing.read().unwrap().is_folder() {
+        reply.error(libc::EISDIR);
+        return;
+      }
+    }
+    drop(inner);
 
-    for (i, entry) in files.iter().enumerate().skip(offset as usize) {
-      let file = entry.borrow();
-      let ret = match file.deref() {
-        INode::File { name, .. } => {
-          debug!(LOG, "file[{}]: {:?}", i, entry.borrow().get_name());
-          reply.add(file.get_ino(), (i + 1) as i64, FileType::RegularFile, name)
+    let send_inner = self.inner.clone();
+    self.runtime.spawn(async move {
+      let moved = async {
+        let _guard = overlay.lock().await;
+        let upper = if link {
+          overlay.symlink(&to, &target).await?
+        } else {
+          let upper = overlay.prepare(&to).await?;
+          if overlay.is_upper(&target) {
+            tokio::fs::rename(&target, &upper).await?;
+          } else {
+            overlay.copy_up(&to, &target, member.as_ref()).await?;
+          }
+          upper
+        };
+        overlay.remove(&from).await?;
+        Ok::<_, Error>(upper.to_string_lossy().into_owned())
+      }.await;
+      let upper = match moved {
+        Ok(upper) => upper,
+        Err(err) => {
+          error!(LOG, "Failed to rename {} to {}: {}", from, to, err);
+          reply.error(errno(&err));
+          return;
         }
-        INode::Folder { name, .. } => {
-          debug!(LOG, "folder[{}]: {:?}", i, entry.borrow().get_name());
-          reply.add(file.get_ino(), (i + 1) as i64, FileType::Directory, name)
+      };
+
+      let mut inner = send_inner.write().await;
+      let Some(inode) = inner.forget(parent, &name) else {
+        reply.error(libc::ENOENT);
+        return;
+      };
+      // Whatever the move replaced is gone as well
+      inner.forget(newparent, &newname);
+      {
+        let mut inode_mut = inode.write().unwrap();
+        inode_mut.set_name(newname);
+        if let INode::File { target, member, sha256, .. } = &mut *inode_mut {
+          *target = upper;
+          *member = None;
+          *sha256 = None;
+        }
+      }
+      inner.inode_table.insert(newparent, inode);
+      reply.ok();
+    });
+  }
+
+  fn setattr(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+    _ctime: Option<SystemTime>,
+    _fh: Option<u64>,
+    _crtime: Option<SystemTime>,
+    _chgtime: Option<SystemTime>,
+    _bkuptime: Option<SystemTime>,
+    _flags: Option<u32>,
+    reply: ReplyAttr,
+  ) {
+    debug!(LOG, "setattr(ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?})", ino, mode, uid, gid, size);
+    let (inode, cache) = {
+      let inner = self.inner.blocking_read();
+      (inner.inode_table.get_by_ino(ino), inner.metadata.clone())
+    };
+    let Some(inode) = inode else {
+      reply.error(libc::ENOENT);
+      return;
+    };
+    let (target, member, meta) = match inode.read().unwrap().deref() {
+      INode::File { target, member, meta, .. } => (target.clone(), member.clone(), meta.clone()),
+      // Their attributes come from the mapping and the flags, there is
+      // nothing in the upper layer to change
+      other => {
+        let attr = match other {
+          INode::Symlink { target, .. } => self.defaults.symlink_attr(other, target),
+          _ => self.defaults.folder_attr(other),
+        };
+        let changes = mode.is_some_and(|mode| (mode & 0o7777) as u16 != attr.perm)
+          || uid.is_some_and(|uid| uid != attr.uid)
+          || gid.is_some_and(|gid| gid != attr.gid)
+          || size.is_some()
+          || atime.is_some()
+          || mtime.is_some();
+        if changes {
+          reply.error(libc::EPERM);
+        } else {
+          reply.attr(&self.policy.attr_ttl, &attr);
         }
+        return;
+      }
+    };
+
+    let upper = if mode.is_some() || size.is_some() || atime.is_some() || mtime.is_some() {
+      let inner = self.inner.blocking_read();
+      let (Some(overlay), Some(path)) = (inner.overlay.clone(), inner.inode_table.path_of(ino)) else {
+        reply.error(libc::EROFS);
+        return;
       };
+      Some((overlay, path))
+    } else {
+      None
+    };
 
-      if ret {
-        break;
+    let policy = self.policy;
+    self.runtime.spawn(async move {
+      let changed = async {
+        // Files are owned by whoever owns the blobs, a chown can only keep
+        // the owner it already has
+        let attr = Self::getattr(&cache, ino, &target, member.as_ref(), &meta).await?;
+        if uid.is_some_and(|uid| uid != attr.uid) || gid.is_some_and(|gid| gid != attr.gid) {
+          return Err(Error::from_raw_os_error(libc::EPERM));
+        }
+        let Some((overlay, path)) = upper else {
+          return Ok((target, member, meta));
+        };
+        let target = Self::copy_up(&overlay, &inode, &path).await?;
+        if let Some(size) = size {
+          OpenOptions::new().write(true).open(&target).await?.set_len(size).await?;
+        }
+        if let Some(mode) = mode {
+          tokio::fs::set_permissions(&target, Permissions::from_mode(mode & 0o7777)).await?;
+        }
+        if atime.is_some() || mtime.is_some() {
+          let path = target.clone();
+          tokio::task::spawn_blocking(move || set_times(&path, atime, mtime)).await??;
+        }
+        cache.invalidate(ino);
+        let meta = match inode.read().unwrap().deref() {
+          INode::File { meta, .. } => meta.clone(),
+          _ => Metadata::default(),
+        };
+        Ok::<_, Error>((target, None, meta))
+      }.await;
+
+      match changed {
+        Ok((target, member, meta)) => match Self::getattr(&cache, ino, &target, member.as_ref(), &meta).await {
+          Ok(attr) => reply.attr(&policy.attr_ttl, &attr),
+          Err(err) => {
+            error!(LOG, "Failed to get attr for {}: {}", target, err);
+            reply.error(libc::EIO);
+          }
+        },
+        Err(err) => {
+          error!(LOG, "Failed to set attr for {}: {}", ino, err);
+          reply.error(errno(&err));
+        }
       }
-    }
+    });
+  }
+}
 
-    reply.ok();
+/// Sets the access and modification times of `path`, leaving those that are
+/// `None` as they are. Blocks, call it from the blocking pool.
+fn set_times(path: &str, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> Result<(), Error> {
+  fn timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    let (tv_sec, tv_nsec) = match time {
+      None => (0, libc::UTIME_OMIT),
+      Some(TimeOrNow::Now) => (0, libc::UTIME_NOW),
+      Some(TimeOrNow::SpecificTime(time)) => match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as libc::time_t, since.subsec_nanos() as libc::c_long),
+        Err(err) => {
+          let before = err.duration();
+          let nanos = before.subsec_nanos();
+          let secs = -(before.as_secs() as libc::time_t) - libc::time_t::from(nanos != 0);
+          (secs, if nanos == 0 { 0 } else { 1_000_000_000 - nanos as libc::c_long })
+        }
+      },
+    };
+    libc::timespec { tv_sec, tv_nsec }
   }
+
+  let path = CString::new(path).map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+  let times = [timespec(atime), timespec(mtime)];
+  // SAFETY: path is NUL-terminated and times holds the two entries utimensat reads
+  if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } != 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(())
 }
 
 fn main() {
-  env_logger::init();
-  let args = Args::parse();
+  let mut args = Args::parse();
+  // Before logging starts its thread, a fork only keeps the calling one
+  let daemon = if args.daemon {
+    match std::env::current_dir() {
+      Ok(cwd) => args.absolutize(&cwd),
+      Err(err) => {
+        eprintln!("Failed to get the working directory: {}", err);
+        exit(exitcode::OSERR);
+      }
+    }
+    match daemon::daemonize() {
+      Ok(pipe) => Some(pipe),
+      Err(err) => {
+        eprintln!("Failed to daemonize: {}", err);
+        exit(exitcode::OSERR);
+      }
+    }
+  } else {
+    None
+  };
+  let mut readiness = Readiness::from_args(&args, daemon);
+  if let Err(err) = logging::init(args.log_level.clone(), args.log_format, LogTarget::from_args(&args)) {
+    eprintln!("Failed to set up logging: {}", err);
+    exit(exitcode::CANTCREAT);
+  }
+  for directive in args.log_level.invalid() {
+    warn!(LOG, "Skipping invalid log directive {:?}", directive);
+  }
 
-  let mut options = vec![MountOption::RO, MountOption::FSName("fs-proxy".to_string())];
+  let mut options = vec![MountOption::FSName("fs-proxy".to_string())];
+  if args.upper_dir.is_some() {
+    options.push(MountOption::RW);
+  } else {
+    options.push(MountOption::RO);
+  }
   if args.auto_unmount {
     options.push(MountOption::AutoUnmount);
   }
@@ -385,7 +1435,9 @@ fn main() {
     options.push(MountOption::AllowRoot);
   }
 
-  let config = match read_mapping_file(&args) {
+  let source = MappingSource::from_args(&args);
+  let overlay = args.upper_dir.clone().map(Overlay::new);
+  let config = match reload::load(&source, &BTreeMap::new(), args.blob_root.as_deref(), overlay.as_ref()) {
     Ok(cfg) => cfg,
     Err(err) => {
       error!(LOG, "Failed to read mapping file: {}", err);
@@ -400,25 +1452,121 @@ fn main() {
       exit(exitcode::SOFTWARE);
     }
   };
+  let handle = runtime.handle().clone();
+
+  let access_log = match &args.access_log {
+    Some(path) => match AccessLog::open(path, args.access_log_max_size * 1024 * 1024, args.access_log_keep) {
+      Ok(access_log) => Some(access_log),
+      Err(err) => {
+        error!(LOG, "Failed to open access log {}: {}", path, err);
+        exit(exitcode::CANTCREAT);
+      }
+    },
+    None => None,
+  };
+  let metrics_listener = match args.metrics_address {
+    Some(address) => match metrics::bind(address) {
+      Ok(listener) => Some(listener),
+      Err(err) => {
+        error!(LOG, "Failed to bind metrics endpoint {}: {}", address, err);
+        exit(exitcode::CANTCREAT);
+      }
+    },
+    None => None,
+  };
+
+  let mut mapping_fs = MappingFS::new(handle.clone(), &args, source.clone(), overlay, access_log, config);
+  let inner = mapping_fs.inner.clone();
+  let shutdown = mapping_fs.shutdown.clone();
+  let initialized = mapping_fs.initialized();
+  if args.prewarm_metadata {
+    let (cache, files) = {
+      let inner = inner.blocking_read();
+      (inner.metadata.clone(), inner.inode_table.files())
+    };
+    handle.spawn(async move { cache.prewarm(files).await });
+  }
+  handle.spawn(reload::watch(
+    inner.clone(),
+    source,
+    args.watch_interval.map(Duration::from_secs),
+  ));
+
+  let mountpoint = std::path::Path::new(&args.mountpoint);
+  let session = match Session::new(mapping_fs, mountpoint, &options) {
+    Ok(session) => session,
+    Err(err) => {
+      error!(LOG, "Failed to mount filesystem: {}", err);
+      exit(exitcode::SOFTWARE);
+    }
+  };
 
-  let mapping_fs = MappingFS::new(runtime, config);
-  if let Err(err) = fuser::mount2(mapping_fs, args.mountpoint, &options) {
-    error!(LOG, "Failed to mount filesystem: {}", err);
-    exit(exitcode::SOFTWARE);
+  if let Some(listener) = metrics_listener {
+    handle.spawn(metrics::serve(inner.clone(), listener));
+  }
+  if let Some(control_socket) = &args.control_socket {
+    handle.spawn(control::serve(inner.clone(), control_socket.clone(), shutdown.clone()));
   }
+
+  let session = match session.spawn() {
+    Ok(session) => session,
+    Err(err) => {
+      error!(LOG, "Failed to start FUSE session: {}", err);
+      exit(exitcode::SOFTWARE);
+    }
+  };
+
+  // Signals are listened for since `MappingFS::new`, whoever is told the
+  // mount is ready may stop it right away
+  if initialized.recv().is_ok() {
+    readiness.ready();
+  }
+
+  let reason = handle.block_on(shutdown.wait());
+  info!(LOG, "Shutting down: {}", reason);
+  readiness.stopping();
+  let drained = handle.block_on(shutdown::drain(&inner, args.shutdown_timeout));
+  let session = shutdown::unmount(session);
+  let code = match session.join() {
+    Ok(Ok(())) if drained => {
+      info!(LOG, "Unmounted cleanly");
+      exitcode::OK
+    }
+    Ok(Ok(())) => exitcode::TEMPFAIL,
+    Ok(Err(err)) => {
+      error!(LOG, "FUSE session failed: {}", err);
+      exitcode::SOFTWARE
+    }
+    Err(_) => {
+      error!(LOG, "FUSE session panicked");
+      exitcode::SOFTWARE
+    }
+  };
+  // Every way out once ready passes here, the files must not outlive the
+  // mount
+  readiness.cleanup();
+  exit(code);
+}
+
+/// Exits with `code` once the log is written out, `std::process::exit`
+/// would lose the records still queued.
+fn exit(code: i32) -> ! {
+  logging::flush();
+  std::process::exit(code)
 }
 
-fn read_mapping_file(args: &Args) -> Result<Path, StartError> {
-  let mapping_file = std::fs::File::open(&args.mapping_file)
-    .map_err(|e| StartError::Io(e))?;
+fn read_mapping_file(path: &str) -> Result<Path, StartError> {
+  let mapping_file = std::fs::File::open(path)
+    .map_err(StartError::Io)?;
   let rdr = std::io::BufReader::new(mapping_file);
   serde_json::from_reader(rdr)
-    .map_err(|e| StartError::Serde(e))
+    .map_err(StartError::Serde)
 }
 
 enum StartError {
   Io(Error),
   Serde(serde_json::Error),
+  Mapping(String),
 }
 
 impl Display for StartError {
@@ -426,6 +1574,117 @@ impl Display for StartError {
     match self {
       StartError::Io(err) => write!(f, "IO error: {}", err),
       StartError::Serde(err) => write!(f, "Serde error: {}", err),
+      StartError::Mapping(err) => write!(f, "Mapping error: {}", err),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_read_full_at() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    std::fs::write(&path, b"hello world").unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+
+    assert_eq!(read_full_at(&file, 0, 5).unwrap(), b"hello");
+    assert_eq!(read_full_at(&file, 6, 4096).unwrap(), b"world");
+    assert!(read_full_at(&file, 11, 4096).unwrap().is_empty());
+    assert!(read_full_at(&file, 4096, 4096).unwrap().is_empty());
+    assert!(read_full_at(&file, i64::MAX as u64, 4096).unwrap().is_empty());
+    assert!(read_full_at(&file, u64::MAX, 4096).unwrap().is_empty());
+
+    // The blob shrinks under an open handle
+    std::fs::write(&path, b"hi").unwrap();
+    assert_eq!(read_full_at(&file, 0, 4096).unwrap(), b"hi");
+    assert!(read_full_at(&file, 6, 4096).unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_metadata_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    std::fs::write(&path, b"hello").unwrap();
+    let target = path.to_string_lossy().into_owned();
+
+    // A write drops the size cached before it
+    let cache = MetadataCache::default();
+    assert_eq!(cache.get(2, &target).await.unwrap().len(), 5);
+    let handle = Arc::new(FileHandle {
+      ino: 2,
+      target: target.clone(),
+      file: std::fs::OpenOptions::new().write(true).open(&path).unwrap(),
+      member: None,
+      access: Access::new(String::from("/hello"), 0, 0, 0),
+    });
+    write_at(handle, &cache, 5, b" world".to_vec()).await.unwrap();
+    assert_eq!(cache.get(2, &target).await.unwrap().len(), 11);
+
+    // So does an unlink
+    let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "res/mapping-tree.json"]);
+    let source = MappingSource::from_args(&args);
+    let mapping = source.load().unwrap_or_else(|err| panic!("{}", err));
+    let fs = MappingFS::new(Handle::current(), &args, source, None, None, mapping);
+    let mut inner = fs.inner.write().await;
+    let ino = inner.inode_table.files()[0].0;
+    let (parent, name) = {
+      let inode = inner.inode_table.get_by_ino(ino).unwrap();
+      let inode = inode.read().unwrap();
+      (inode.get_parent(), inode.get_name().clone())
+    };
+    let cache = inner.metadata.clone();
+    assert_eq!(cache.get(ino, &target).await.unwrap().len(), 11);
+    assert!(inner.forget(parent, &name).is_some());
+    assert!(inner.inode_table.get_by_ino(ino).is_none());
+    std::fs::write(&path, b"hi").unwrap();
+    assert_eq!(cache.get(ino, &target).await.unwrap().len(), 2);
+  }
+
+  #[test]
+  fn test_upper_entry() {
+    let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "res/mapping-tree.json", "--upper-dir", "/tmp/upper"]);
+    let source = MappingSource::from_args(&args);
+    let mapping = source.load().unwrap_or_else(|err| panic!("{}", err));
+    let runtime = Runtime::new().unwrap();
+    let overlay = Some(Overlay::new(String::from("/tmp/upper")));
+    let fs = MappingFS::new(runtime.handle().clone(), &args, source, overlay, None, mapping);
+
+    let (_, path, name) = fs.upper_entry(inode::ROOT_INO, OsStr::new("notes.txt")).unwrap();
+    assert_eq!((path.as_str(), name.as_str()), ("/notes.txt", "notes.txt"));
+    assert_eq!(fs.upper_entry(inode::ROOT_INO, OsStr::new("..")).err(), Some(libc::EINVAL));
+    assert_eq!(fs.upper_entry(inode::ROOT_INO, OsStr::new(".wh.bundle.zip")).err(), Some(libc::EPERM));
+    assert_eq!(fs.upper_entry(inode::ROOT_INO, OsStr::new(".wh..wh..opq")).err(), Some(libc::EPERM));
+  }
+
+  #[test]
+  fn test_cache_policy() {
+    let args = Args::parse_from(vec!["fs-proxy", "/tmp/hello", "--mapping-file", "/tmp/m.json", "--immutable", "--attr-ttl", "5"]);
+    let policy = CachePolicy::from_args(&args);
+    assert_eq!(policy.entry_ttl, IMMUTABLE_TTL);
+    assert_eq!(policy.entry_attr_ttl(), Duration::from_secs(5));
+    assert_eq!(policy.open_flags, consts::FOPEN_KEEP_CACHE);
+  }
+
+  #[test]
+  fn test_set_times() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    std::fs::write(&path, b"hello").unwrap();
+    let target = path.to_string_lossy().into_owned();
+    let atime = std::fs::metadata(&path).unwrap().atime();
+
+    set_times(&target, None, Some(TimeOrNow::SpecificTime(epoch_time(1_000_000_000)))).unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.mtime(), 1_000_000_000);
+    assert_eq!(metadata.atime(), atime);
+
+    set_times(&target, Some(TimeOrNow::SpecificTime(epoch_time(-1))), None).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().atime(), -1);
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(set_times(&target, Some(TimeOrNow::Now), None).is_err());
+  }
+}
@@ -1,5 +1,37 @@
+use crate::archive::ArchiveMember;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+/// Attributes of the original artifact, shown instead of those of the blob
+/// or of the mount. Times are seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime: Option<i64>,
+}
+
+impl Metadata {
+    /// What still applies to a file once it was copied to the upper layer:
+    /// the owner. The mode is written onto the copy and the times are those
+    /// of the copy, so that writes show up.
+    pub fn copied_up(&self) -> Metadata {
+        Metadata {
+            uid: self.uid,
+            gid: self.gid,
+            ..Metadata::default()
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
@@ -7,15 +39,145 @@ pub(crate) enum Path {
     File {
         name: String,
         path: String,
+        /// Set for members of an archive, `path` is then the archive blob.
+        #[serde(skip)]
+        member: Option<ArchiveMember>,
+        /// Expected digest of the blob, checked by `--verify` instead of the
+        /// one in the blob's file name.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+        #[serde(flatten)]
+        meta: Metadata,
     },
     Folder {
         name: String,
         paths: HashMap<String, Path>,
+        #[serde(flatten)]
+        meta: Metadata,
+    },
+    /// A zip blob served as a folder of its members.
+    Zip {
+        name: String,
+        path: String,
+    },
+    /// A tar blob, plain or gzip compressed, served as a folder of its members.
+    Tar {
+        name: String,
+        path: String,
+    },
+    /// A symbolic link, `target` is returned as is by readlink.
+    Symlink {
+        name: String,
+        target: String,
+    },
+    /// A blob of the content-addressed store, referenced by its sha256 alone
+    /// and resolved under `--blob-root` when the mapping is loaded.
+    Blob {
+        name: String,
+        sha256: String,
+        #[serde(flatten)]
+        meta: Metadata,
     },
 }
 
+impl Path {
+    /// Mounts every mapping under a folder named after its key, e.g. a record
+    /// id, below a synthetic root.
+    pub fn graft(mappings: BTreeMap<String, Path>) -> Path {
+        let mut root = Path::Folder {
+            name: "/".to_string(),
+            paths: HashMap::new(),
+            meta: Metadata::default(),
+        };
+        for (name, path) in mappings {
+            root.attach(name, path);
+        }
+        root
+    }
+
+    /// Adds `mapping` to this folder as `name`, replacing any entry with the
+    /// same name. A file has no entries, so attaching to it does nothing.
+    pub fn attach(&mut self, name: String, mapping: Path) {
+        if let Path::Folder { paths, .. } = self {
+            paths.insert(name.clone(), mapping.rename(name));
+        }
+    }
+
+    fn rename(self, name: String) -> Path {
+        match self {
+            Path::File {
+                path,
+                member,
+                sha256,
+                meta,
+                ..
+            } => Path::File {
+                name,
+                path,
+                member,
+                sha256,
+                meta,
+            },
+            Path::Folder { paths, meta, .. } => Path::Folder { name, paths, meta },
+            Path::Zip { path, .. } => Path::Zip { name, path },
+            Path::Tar { path, .. } => Path::Tar { name, path },
+            Path::Symlink { target, .. } => Path::Symlink { name, target },
+            Path::Blob { sha256, meta, .. } => Path::Blob { name, sha256, meta },
+        }
+    }
+
+    /// Turns every `Blob` into a `File` at `<blob_root>/<sha256>`, and checks
+    /// that the digests given for files are digests at all.
+    pub fn resolve_blobs(&mut self, blob_root: Option<&str>) -> Result<(), String> {
+        match self {
+            Path::Folder { paths, .. } => {
+                for path in paths.values_mut() {
+                    path.resolve_blobs(blob_root)?;
+                }
+            }
+            // `--verify` would otherwise pass the file unchecked
+            Path::File {
+                name,
+                sha256: Some(sha256),
+                ..
+            } => {
+                if !is_sha256(sha256) {
+                    return Err(format!("{}: invalid sha256 {:?}", name, sha256));
+                }
+                *sha256 = sha256.to_ascii_lowercase();
+            }
+            Path::Blob { name, sha256, meta } => {
+                // Anything but hex would let a mapping reach outside the store
+                if !is_sha256(sha256) {
+                    return Err(format!("{}: invalid sha256 {:?}", name, sha256));
+                }
+                let Some(blob_root) = blob_root else {
+                    return Err(format!("{}: blob {} needs --blob-root", name, sha256));
+                };
+                let sha256 = sha256.to_ascii_lowercase();
+                let path = format!("{}/{}", blob_root.trim_end_matches('/'), sha256);
+                *self = Path::File {
+                    name: name.clone(),
+                    path,
+                    member: None,
+                    sha256: Some(sha256),
+                    meta: std::mem::take(meta),
+                };
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn is_sha256(digest: &str) -> bool {
+    digest.len() == 64 && digest.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     fn fake_mapping() -> Path {
         let mut root = HashMap::new();
         let folder1_name = "folder1".to_string();
@@ -31,10 +193,14 @@ mod tests {
                         Path::File {
                             name,
                             path: "/tmp/hello.txt".to_string(),
+                            member: None,
+                            sha256: None,
+                            meta: Metadata::default(),
                         },
                     );
                     folder1
                 },
+                meta: Metadata::default(),
             },
         );
         let folder2_name = "folder2".to_string();
@@ -50,6 +216,9 @@ mod tests {
                         Path::File {
                             name: d2f1_name,
                             path: "/tmp/hello.txt".to_string(),
+                            member: None,
+                            sha256: None,
+                            meta: Metadata::default(),
                         },
                     );
                     let d2f2_name = "d2f2.txt".to_string();
@@ -58,10 +227,14 @@ mod tests {
                         Path::File {
                             name: d2f2_name,
                             path: "/tmp/hello.txt".to_string(),
+                            member: None,
+                            sha256: None,
+                            meta: Metadata::default(),
                         },
                     );
                     folder2
                 },
+                meta: Metadata::default(),
             },
         );
         let file1_name = "file1.txt".to_string();
@@ -70,14 +243,68 @@ mod tests {
             Path::File {
                 name: file1_name,
                 path: "/tmp/hello.txt".to_string(),
+                member: None,
+                sha256: None,
+                meta: Metadata::default(),
             },
         );
         Path::Folder {
             name: "/".to_string(),
             paths: root,
+            meta: Metadata::default(),
         }
     }
 
+    #[test]
+    fn test_resolve_blobs() {
+        let sha256 = "1bf85cea492634c087e57a920f8b20247a434614e7353555627935e2e051e19f";
+        let json = format!(
+            r#"{{"type": "Folder", "name": "/", "paths": {{"a.bin": {{"type": "Blob", "name": "a.bin", "sha256": "{}", "mode": 493}}}}}}"#,
+            sha256
+        );
+        let mut mapping: Path = serde_json::from_str(&json).unwrap();
+        assert!(mapping.resolve_blobs(None).is_err());
+        mapping.resolve_blobs(Some("/store/blobs/")).unwrap();
+        let Path::Folder { paths, .. } = mapping else {
+            panic!("root should be a folder");
+        };
+        let Some(Path::File { path, meta, .. }) = paths.get("a.bin") else {
+            panic!("a.bin should resolve to a file");
+        };
+        assert_eq!(path, &format!("/store/blobs/{}", sha256));
+        assert_eq!(meta.mode, Some(0o755));
+
+        let mut escape = Path::Blob {
+            name: "escape".to_string(),
+            sha256: "../../etc/passwd".to_string(),
+            meta: Metadata::default(),
+        };
+        assert!(escape.resolve_blobs(Some("/store/blobs")).is_err());
+
+        let json = r#"{"type": "File", "name": "a.bin", "path": "/tmp/a.bin", "sha256": "1bf85cea"}"#;
+        let mut truncated: Path = serde_json::from_str(json).unwrap();
+        assert!(truncated.resolve_blobs(None).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let json = r#"{"type": "File", "name": "uninstall-helper.sh", "path": "/tmp/blob", "mode": 493, "uid": 0, "mtime": 1600000000}"#;
+        let Path::File { meta, .. } = serde_json::from_str(json).unwrap() else {
+            panic!("should be a file");
+        };
+        assert_eq!(
+            meta,
+            Metadata {
+                mode: Some(0o755),
+                uid: Some(0),
+                mtime: Some(1_600_000_000),
+                ..Metadata::default()
+            }
+        );
+        let serialized = serde_json::to_string(&fake_mapping()).unwrap();
+        assert!(!serialized.contains("mode"));
+    }
+
     #[test]
     fn test_serde() {
         let mapping = fake_mapping();
@@ -86,4 +313,21 @@ mod tests {
         let deserialized: Path = serde_json::from_str(&serialized).unwrap();
         println!("deserialized = {:?}", deserialized);
     }
+
+    #[test]
+    fn test_graft() {
+        let mut mappings = BTreeMap::new();
+        mappings.insert("record1".to_string(), fake_mapping());
+        mappings.insert("record2".to_string(), fake_mapping());
+        let Path::Folder { name, paths, .. } = Path::graft(mappings) else {
+            panic!("graft should produce a folder");
+        };
+        assert_eq!(name, "/");
+        assert_eq!(paths.len(), 2);
+        let Some(Path::Folder { name, paths, .. }) = paths.get("record1") else {
+            panic!("record1 should be a folder");
+        };
+        assert_eq!(name, "record1");
+        assert!(paths.contains_key("file1.txt"));
+    }
 }
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io::Error;
+use std::sync::RwLock;
+use slog::{debug, info};
+use crate::LOG;
+
+/// Metadata of the blobs behind file inodes, so a stat is answered without
+/// opening the blob. Entries remember the target they were read from, an inode
+/// pointed at another blob, e.g. after a copy-up, misses by itself.
+#[derive(Default)]
+pub(crate) struct MetadataCache {
+  entries: RwLock<HashMap<u64, (String, Metadata)>>,
+}
+
+impl MetadataCache {
+  pub(crate) async fn get(&self, ino: u64, target: &str) -> Result<Metadata, Error> {
+    if let Some((cached, metadata)) = self.entries.read().unwrap().get(&ino) {
+      if cached == target {
+        return Ok(metadata.clone());
+      }
+    }
+    let metadata = stat(target).await?;
+    self.entries.write().unwrap().insert(ino, (target.to_string(), metadata.clone()));
+    Ok(metadata)
+  }
+
+  /// Drops `ino` after its blob changed underneath, e.g. on a write.
+  pub(crate) fn invalidate(&self, ino: u64) {
+    self.entries.write().unwrap().remove(&ino);
+  }
+
+  pub(crate) fn clear(&self) {
+    self.entries.write().unwrap().clear();
+  }
+
+  /// Fills the cache for every `(ino, target)` in `files`. A blob that cannot
+  /// be read is left out and reported again by the stat that needs it.
+  pub(crate) async fn prewarm(&self, files: Vec<(u64, String)>) {
+    let total = files.len();
+    let mut cached = 0;
+    for (ino, target) in files {
+      match self.get(ino, &target).await {
+        Ok(_) => cached += 1,
+        Err(err) => debug!(LOG, "Failed to pre-warm metadata of {}: {}", target, err),
+      }
+    }
+    info!(LOG, "Pre-warmed metadata of {} out of {} files", cached, total);
+  }
+}
+
+/// `symlink_metadata` does not need an open file descriptor. A blob store
+/// that links its blobs is still followed, the link itself says nothing.
+async fn stat(target: &str) -> Result<Metadata, Error> {
+  let metadata = tokio::fs::symlink_metadata(target).await?;
+  if metadata.file_type().is_symlink() {
+    return tokio::fs::metadata(target).await;
+  }
+  Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    let target = path.to_string_lossy().into_owned();
+    std::fs::write(&path, b"hello").unwrap();
+
+    let cache = MetadataCache::default();
+    cache.prewarm(vec![(2, target.clone()), (3, String::from("/nonexistent"))]).await;
+    assert_eq!(cache.entries.read().unwrap().len(), 1);
+
+    std::fs::write(&path, b"hello world").unwrap();
+    assert_eq!(cache.get(2, &target).await.unwrap().len(), 5);
+    cache.invalidate(2);
+    assert_eq!(cache.get(2, &target).await.unwrap().len(), 11);
+    assert!(cache.get(3, "/nonexistent").await.is_err());
+
+    cache.clear();
+    assert!(cache.entries.read().unwrap().is_empty());
+  }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use slog::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use crate::{Inner, LOG};
+
+lazy_static! {
+  pub(crate) static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Largest request head read before answering, `/metrics` needs a few bytes.
+const MAX_REQUEST: usize = 8192;
+/// Time a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The FUSE operations that are timed.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Op {
+  Lookup,
+  Getattr,
+  Open,
+  Read,
+  Readdir,
+  Release,
+}
+
+impl Op {
+  const ALL: [Op; 6] = [Op::Lookup, Op::Getattr, Op::Open, Op::Read, Op::Readdir, Op::Release];
+
+  fn name(self) -> &'static str {
+    match self {
+      Op::Lookup => "lookup",
+      Op::Getattr => "getattr",
+      Op::Open => "open",
+      Op::Read => "read",
+      Op::Readdir => "readdir",
+      Op::Release => "release",
+    }
+  }
+}
+
+#[derive(Default)]
+struct Histogram {
+  /// Observations per bucket, not cumulative, the last one is `+Inf`.
+  buckets: [AtomicU64; BUCKETS.len() + 1],
+  count: AtomicU64,
+  sum_micros: AtomicU64,
+}
+
+impl Histogram {
+  fn observe(&self, seconds: f64) {
+    let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+    self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.sum_micros.fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+  }
+}
+
+/// Process wide counters, rendered in the Prometheus text format.
+#[derive(Default)]
+pub(crate) struct Metrics {
+  latencies: [Histogram; Op::ALL.len()],
+  bytes_read: AtomicU64,
+  errors: Mutex<BTreeMap<(&'static str, i32), u64>>,
+}
+
+impl Metrics {
+  /// Starts timing one call of `op`, the call is counted when the timer is
+  /// dropped, i.e. once the reply went out.
+  pub(crate) fn start(&'static self, op: Op) -> Timer {
+    Timer { metrics: self, op, started: Instant::now() }
+  }
+
+  pub(crate) fn add_bytes_read(&self, bytes: usize) {
+    self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  fn render(&self, open_handles: usize) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP fsproxy_op_duration_seconds Time taken to answer FUSE operations.\n");
+    out.push_str("# TYPE fsproxy_op_duration_seconds histogram\n");
+    for op in Op::ALL {
+      let histogram = &self.latencies[op as usize];
+      let mut cumulative = 0;
+      for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "fsproxy_op_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}", op.name(), bound, cumulative);
+      }
+      let count = histogram.count.load(Ordering::Relaxed);
+      let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+      let _ = writeln!(out, "fsproxy_op_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}", op.name(), count);
+      let _ = writeln!(out, "fsproxy_op_duration_seconds_sum{{op=\"{}\"}} {}", op.name(), sum);
+      let _ = writeln!(out, "fsproxy_op_duration_seconds_count{{op=\"{}\"}} {}", op.name(), count);
+    }
+
+    out.push_str("# HELP fsproxy_op_errors_total FUSE operations answered with an error.\n");
+    out.push_str("# TYPE fsproxy_op_errors_total counter\n");
+    for ((op, errno), count) in self.errors.lock().unwrap().iter() {
+      let _ = writeln!(out, "fsproxy_op_errors_total{{op=\"{}\",errno=\"{}\"}} {}", op, errno_name(*errno), count);
+    }
+
+    out.push_str("# HELP fsproxy_read_bytes_total Bytes returned by reads.\n");
+    out.push_str("# TYPE fsproxy_read_bytes_total counter\n");
+    let _ = writeln!(out, "fsproxy_read_bytes_total {}", self.bytes_read.load(Ordering::Relaxed));
+
+    out.push_str("# HELP fsproxy_open_handles Files currently open.\n");
+    out.push_str("# TYPE fsproxy_open_handles gauge\n");
+    let _ = writeln!(out, "fsproxy_open_handles {}", open_handles);
+    out
+  }
+}
+
+/// Times one operation from its start to the point it is dropped.
+pub(crate) struct Timer {
+  metrics: &'static Metrics,
+  op: Op,
+  started: Instant,
+}
+
+impl Timer {
+  /// Counts `errno` against the operation and hands it back for the reply,
+  /// e.g. `reply.error(timer.fail(libc::ENOENT))`.
+  pub(crate) fn fail(&self, errno: i32) -> i32 {
+    *self.metrics.errors.lock().unwrap().entry((self.op.name(), errno)).or_default() += 1;
+    errno
+  }
+}
+
+impl Drop for Timer {
+  fn drop(&mut self) {
+    self.metrics.latencies[self.op as usize].observe(self.started.elapsed().as_secs_f64());
+  }
+}
+
+fn errno_name(errno: i32) -> String {
+  let name = match errno {
+    libc::ENOENT => "ENOENT",
+    libc::EIO => "EIO",
+    libc::EBADF => "EBADF",
+    libc::EINVAL => "EINVAL",
+    libc::ENFILE => "ENFILE",
+    libc::ENOTDIR => "ENOTDIR",
+    libc::EROFS => "EROFS",
+    _ => return errno.to_string(),
+  };
+  name.to_string()
+}
+
+/// Answers `GET /metrics` on `address`, one request per connection.
+/// Binds the metrics endpoint up front, so a taken address fails startup
+/// instead of leaving the mount without metrics.
+pub(crate) fn bind(address: SocketAddr) -> Result<std::net::TcpListener, Error> {
+  let listener = std::net::TcpListener::bind(address)?;
+  listener.set_nonblocking(true)?;
+  Ok(listener)
+}
+
+pub(crate) async fn serve(inner: Arc<RwLock<Inner>>, listener: std::net::TcpListener) {
+  let listener = match TcpListener::from_std(listener) {
+    Ok(listener) => listener,
+    Err(err) => {
+      error!(LOG, "Failed to listen for metrics requests: {}", err);
+      return;
+    }
+  };
+  if let Ok(address) = listener.local_addr() {
+    info!(LOG, "Serving metrics on http://{}/metrics", address);
+  }
+
+  loop {
+    match listener.accept().await {
+      Ok((stream, _)) => {
+        tokio::spawn(handle(inner.clone(), stream));
+      }
+      Err(err) => {
+        error!(LOG, "Failed to accept metrics connection: {}", err);
+      }
+    }
+  }
+}
+
+async fn handle(inner: Arc<RwLock<Inner>>, mut stream: TcpStream) {
+  // A client that never finishes its request would hold the connection open
+  let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+    Ok(Ok(request)) => request,
+    Ok(Err(err)) => {
+      debug!(LOG, "Failed to read metrics request: {}", err);
+      return;
+    }
+    Err(_) => {
+      debug!(LOG, "Timed out reading metrics request");
+      return;
+    }
+  };
+
+  let line = request.split(|byte| *byte == b'\n').next().unwrap_or_default();
+  let mut parts = std::str::from_utf8(line).unwrap_or_default().split_whitespace();
+  let (status, body) = match (parts.next(), parts.next()) {
+    (Some("GET"), Some("/metrics")) => {
+      let open_handles = inner.read().await.file_handles.len();
+      ("200 OK", METRICS.render(open_handles))
+    }
+    (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+    _ => ("405 Method Not Allowed", String::from("Method not allowed\n")),
+  };
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body,
+  );
+  if let Err(err) = stream.write_all(response.as_bytes()).await {
+    debug!(LOG, "Failed to write metrics response: {}", err);
+  }
+}
+
+/// Reads up to the end of the request head, or `MAX_REQUEST` bytes of it.
+async fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+  let mut request = vec![];
+  let mut buf = [0; 1024];
+  while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+    match stream.read(&mut buf).await? {
+      0 => break,
+      read => request.extend_from_slice(&buf[..read]),
+    }
+  }
+  Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    lazy_static! {
+      static ref TEST_METRICS: Metrics = Metrics::default();
+    }
+    drop(TEST_METRICS.start(Op::Lookup));
+    let timer = TEST_METRICS.start(Op::Open);
+    assert_eq!(timer.fail(libc::ENOENT), libc::ENOENT);
+    drop(timer);
+    TEST_METRICS.add_bytes_read(4096);
+
+    let text = TEST_METRICS.render(3);
+    assert!(text.contains("fsproxy_op_duration_seconds_bucket{op=\"lookup\",le=\"+Inf\"} 1\n"));
+    assert!(text.contains("fsproxy_op_duration_seconds_count{op=\"open\"} 1\n"));
+    assert!(text.contains("fsproxy_op_duration_seconds_count{op=\"read\"} 0\n"));
+    assert!(text.contains("fsproxy_op_errors_total{op=\"open\",errno=\"ENOENT\"} 1\n"));
+    assert!(text.contains("fsproxy_read_bytes_total 4096\n"));
+    assert!(text.contains("fsproxy_open_handles 3\n"));
+  }
+
+  #[test]
+  fn test_bind() {
+    let listener = bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    assert!(bind(listener.local_addr().unwrap()).is_err());
+  }
+}
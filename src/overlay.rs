@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use crate::archive::{extract, ArchiveMember};
+use crate::mapping::{Metadata, Path};
+
+/// Marks a name deleted from the lower layer, e.g. `.wh.config.json`.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marks a folder that was removed and recreated, so nothing below it comes
+/// from the lower layer any more.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Whether the upper layer would read an entry called `name` back as one of
+/// its markers rather than as the entry.
+pub(crate) fn is_reserved(name: &str) -> bool {
+  name.starts_with(WHITEOUT_PREFIX)
+}
+
+/// Writable upper layer. Every change lands in `upper` under the virtual path
+/// of the entry, the blobs referenced by the mapping are never written.
+#[derive(Clone)]
+pub(crate) struct Overlay {
+  upper: PathBuf,
+  copy_up: Arc<Mutex<()>>,
+}
+
+impl Overlay {
+  pub(crate) fn new(upper: String) -> Self {
+    Self {
+      upper: PathBuf::from(upper),
+      copy_up: Arc::new(Mutex::new(())),
+    }
+  }
+
+  /// Replays the upper layer on top of a freshly loaded mapping.
+  pub(crate) fn apply(&self, mapping: &mut Path) -> Result<(), Error> {
+    if !self.upper.is_dir() {
+      return Ok(());
+    }
+    merge(&self.upper, mapping)
+  }
+
+  pub(crate) fn upper_path(&self, path: &str) -> PathBuf {
+    self.upper.join(path.trim_start_matches('/'))
+  }
+
+  pub(crate) fn is_upper(&self, target: &str) -> bool {
+    std::path::Path::new(target).starts_with(&self.upper)
+  }
+
+  /// Serializes copy-ups, so two writers opening the same blob at once do not
+  /// both copy it over each other.
+  pub(crate) async fn lock(&self) -> MutexGuard<'_, ()> {
+    self.copy_up.lock().await
+  }
+
+  /// Copies `target`, or the archive `member` inside it, to the upper layer
+  /// at `path` unless it already lives there, and returns the upper file.
+  pub(crate) async fn copy_up(&self, path: &str, target: &str, member: Option<&ArchiveMember>) -> Result<String, Error> {
+    if self.is_upper(target) {
+      return Ok(target.to_string());
+    }
+    let upper = self.prepare(path).await?;
+    match member {
+      Some(member) => {
+        let (target, member, dest) = (target.to_string(), member.clone(), upper.clone());
+        tokio::task::spawn_blocking(move || extract(&target, &member, &dest)).await??;
+      }
+      None => {
+        tokio::fs::copy(target, &upper).await?;
+      }
+    }
+    Ok(upper.to_string_lossy().into_owned())
+  }
+
+  /// Creates the parent folders of `path` in the upper layer and clears any
+  /// whiteout hiding it.
+  pub(crate) async fn prepare(&self, path: &str) -> Result<PathBuf, Error> {
+    let upper = self.upper_path(path);
+    if let Some(parent) = upper.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+    self.remove_whiteout(path).await?;
+    Ok(upper)
+  }
+
+  /// Creates a symlink to `target` at `path` in the upper layer, replacing
+  /// whatever the upper layer had there.
+  pub(crate) async fn symlink(&self, path: &str, target: &str) -> Result<PathBuf, Error> {
+    let upper = self.prepare(path).await?;
+    match tokio::fs::remove_file(&upper).await {
+      Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+      _ => {}
+    }
+    tokio::fs::symlink(target, &upper).await?;
+    Ok(upper)
+  }
+
+  /// Creates the folder `path` in the upper layer. A folder that replaces a
+  /// removed one is marked opaque so the old lower entries stay hidden.
+  pub(crate) async fn mkdir(&self, path: &str, mode: u32) -> Result<(), Error> {
+    let whiteout = self.whiteout_path(path);
+    let replaces_removed = tokio::fs::try_exists(&whiteout).await?;
+    let upper = self.prepare(path).await?;
+    tokio::fs::DirBuilder::new().mode(mode).create(&upper).await?;
+    if replaces_removed {
+      tokio::fs::write(upper.join(OPAQUE_MARKER), b"").await?;
+    }
+    Ok(())
+  }
+
+  /// Removes `path` from the upper layer, if it is there, and hides whatever
+  /// the lower layer has under that name.
+  pub(crate) async fn remove(&self, path: &str) -> Result<(), Error> {
+    let upper = self.upper_path(path);
+    match tokio::fs::symlink_metadata(&upper).await {
+      Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&upper).await?,
+      Ok(_) => tokio::fs::remove_file(&upper).await?,
+      Err(_) => {}
+    }
+    let whiteout = self.whiteout_path(path);
+    if let Some(parent) = whiteout.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(whiteout, b"").await
+  }
+
+  async fn remove_whiteout(&self, path: &str) -> Result<(), Error> {
+    match tokio::fs::remove_file(self.whiteout_path(path)).await {
+      Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+      _ => Ok(()),
+    }
+  }
+
+  fn whiteout_path(&self, path: &str) -> PathBuf {
+    let upper = self.upper_path(path);
+    let name = upper.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    upper.with_file_name(format!("{}{}", WHITEOUT_PREFIX, name))
+  }
+}
+
+fn merge(dir: &std::path::Path, folder: &mut Path) -> Result<(), Error> {
+  let Path::Folder { paths, .. } = folder else {
+    return Ok(());
+  };
+
+  let mut names = vec![];
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    if let Ok(name) = entry.file_name().into_string() {
+      names.push((name, entry));
+    }
+  }
+
+  if names.iter().any(|(name, _)| name == OPAQUE_MARKER) {
+    paths.clear();
+  }
+  for (name, _) in &names {
+    if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+      paths.remove(hidden);
+    }
+  }
+
+  for (name, entry) in names {
+    if name.starts_with(WHITEOUT_PREFIX) {
+      continue;
+    }
+    let file_type = entry.file_type()?;
+    if file_type.is_symlink() {
+      let target = std::fs::read_link(entry.path())?.to_string_lossy().into_owned();
+      paths.insert(name.clone(), Path::Symlink { name, target });
+    } else if file_type.is_dir() {
+      // A folder made by mkdir keeps the mode it was made with, one that
+      // merely holds upper entries of a lower folder leaves it alone
+      let meta = Metadata {
+        mode: Some(std::fs::symlink_metadata(entry.path())?.mode() & 0o7777),
+        ..Metadata::default()
+      };
+      let replaced = entry.path().join(OPAQUE_MARKER).exists();
+      let folder = paths.entry(name.clone()).or_insert_with(|| Path::Folder {
+        name: name.clone(),
+        paths: HashMap::new(),
+        meta: meta.clone(),
+      });
+      if replaced || !matches!(folder, Path::Folder { .. }) {
+        *folder = Path::Folder {
+          name: name.clone(),
+          paths: HashMap::new(),
+          meta,
+        };
+      }
+      merge(&entry.path(), folder)?;
+    } else {
+      // A copied up file keeps the overrides of the lower one it replaces
+      let meta = match paths.get(&name) {
+        Some(Path::File { meta, .. }) => meta.copied_up(),
+        _ => Metadata::default(),
+      };
+      let path = entry.path().to_string_lossy().into_owned();
+      paths.insert(name.clone(), Path::File {
+        name,
+        path,
+        member: None,
+        sha256: None,
+        meta,
+      });
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_apply() {
+    let tmp = tempfile::tempdir().unwrap();
+    let upper = tmp.path().join("upper");
+    let overlay = Overlay::new(upper.to_string_lossy().into_owned());
+
+    let mapping_tree = std::fs::read_to_string("res/mapping-tree.json").unwrap();
+    let mut mapping: Path = serde_json::from_str(&mapping_tree).unwrap();
+
+    let Path::Folder { paths, .. } = &mut mapping else {
+      panic!("root should be a folder");
+    };
+    let Some(Path::Folder { paths, .. }) = paths.get_mut("Surge.app") else {
+      panic!("Surge.app should be a folder");
+    };
+    let Some(Path::Folder { paths, .. }) = paths.get_mut("Contents") else {
+      panic!("Contents should be a folder");
+    };
+    let Some(Path::File { meta, .. }) = paths.get_mut("Info.plist") else {
+      panic!("Info.plist should be a file");
+    };
+    *meta = Metadata { mode: Some(0o600), uid: Some(501), gid: Some(20), mtime: Some(1_600_000_000), ..Metadata::default() };
+
+    let copied = overlay.copy_up("/Surge.app/Contents/Info.plist", "Cargo.toml", None).await.unwrap();
+    assert!(overlay.is_upper(&copied));
+    overlay.remove("/bundle.zip").await.unwrap();
+    overlay.remove("/Surge.app/Contents/Resources").await.unwrap();
+    overlay.mkdir("/Surge.app/Contents/Resources", 0o750).await.unwrap();
+    overlay.mkdir("/Surge.app/Private", 0o700).await.unwrap();
+    overlay.symlink("/Surge.app/Current", "Contents").await.unwrap();
+    overlay.apply(&mut mapping).unwrap();
+
+    let Path::Folder { paths, .. } = mapping else {
+      panic!("root should be a folder");
+    };
+    assert!(!paths.contains_key("bundle.zip"));
+    let Some(Path::Folder { paths, .. }) = paths.get("Surge.app") else {
+      panic!("Surge.app should be a folder");
+    };
+    assert!(matches!(paths.get("Private"), Some(Path::Folder { meta, .. }) if meta.mode == Some(0o700)));
+    let Some(Path::Symlink { target, .. }) = paths.get("Current") else {
+      panic!("Current should be a symlink");
+    };
+    assert_eq!(target, "Contents");
+    let Some(Path::Folder { paths, meta, .. }) = paths.get("Contents") else {
+      panic!("Contents should be a folder");
+    };
+    assert_eq!(meta.mode, None);
+    let Some(Path::File { path, meta, .. }) = paths.get("Info.plist") else {
+      panic!("Info.plist should be a file");
+    };
+    assert_eq!(path, &copied);
+    assert_eq!(meta, &Metadata { uid: Some(501), gid: Some(20), ..Metadata::default() });
+    let Some(Path::Folder { paths, meta, .. }) = paths.get("Resources") else {
+      panic!("Resources should be a folder");
+    };
+    assert!(paths.is_empty());
+    assert_eq!(meta.mode, Some(0o750));
+  }
+}
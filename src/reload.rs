@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, RwLock as SyncRwLock};
+use std::time::{Duration, SystemTime};
+use slog::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::RwLock;
+use crate::archive;
+use crate::args::Args;
+use crate::mapping::Path;
+use crate::overlay::Overlay;
+use crate::{read_mapping_file, Inner, StartError, LOG};
+
+/// Where the mapping tree is loaded from.
+#[derive(Clone)]
+pub(crate) enum MappingSource {
+  /// A single mapping file served at the mount root.
+  File(String),
+  /// A directory of `<record-id>.json` files, each grafted under `/<record-id>`.
+  Dir(String),
+}
+
+impl MappingSource {
+  pub(crate) fn from_args(args: &Args) -> Self {
+    match (&args.mapping_file, &args.mapping_dir) {
+      (Some(file), _) => MappingSource::File(file.clone()),
+      (None, Some(dir)) => MappingSource::Dir(dir.clone()),
+      (None, None) => unreachable!("clap requires --mapping-file or --mapping-dir"),
+    }
+  }
+
+  pub(crate) fn load(&self) -> Result<Path, StartError> {
+    match self {
+      MappingSource::File(path) => read_mapping_file(path),
+      MappingSource::Dir(dir) => {
+        let mut mappings = BTreeMap::new();
+        for (record, path) in list_records(dir).map_err(StartError::Io)? {
+          // One broken record must not take the others down with it
+          match read_mapping_file(&path) {
+            Ok(mapping) => {
+              mappings.insert(record, mapping);
+            }
+            Err(err) => error!(LOG, "Skipping record {}: {}", record, err),
+          }
+        }
+        Ok(Path::graft(mappings))
+      }
+    }
+  }
+
+  /// Latest mtime of the source. For a directory this also covers the files
+  /// in it, so edits to a record are noticed as well as additions and removals.
+  async fn modified(&self) -> Option<SystemTime> {
+    match self {
+      MappingSource::File(path) => modified(path).await,
+      MappingSource::Dir(dir) => {
+        let mut latest = modified(dir).await;
+        let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+          let current = entry.metadata().await.and_then(|metadata| metadata.modified()).ok();
+          latest = latest.max(current);
+        }
+        latest
+      }
+    }
+  }
+}
+
+impl Display for MappingSource {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MappingSource::File(path) => write!(f, "{}", path),
+      MappingSource::Dir(dir) => write!(f, "{}/*.json", dir.trim_end_matches('/')),
+    }
+  }
+}
+
+/// Record id and path of every visible `*.json` file in `dir`.
+fn list_records(dir: &str) -> Result<Vec<(String, String)>, std::io::Error> {
+  let mut records = vec![];
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.extension() != Some(OsStr::new("json")) {
+      continue;
+    }
+    let Some(record) = path.file_stem().and_then(|stem| stem.to_str()) else {
+      continue;
+    };
+    if record.starts_with('.') {
+      continue;
+    }
+    records.push((record.to_string(), path.to_string_lossy().into_owned()));
+  }
+  Ok(records)
+}
+
+/// Loads `source`, grafts every attached mapping file on top of it, resolves
+/// blob references, expands archives and replays the writable upper layer, if
+/// any.
+pub(crate) fn load(
+  source: &MappingSource,
+  attached: &BTreeMap<String, String>,
+  blob_root: Option<&str>,
+  overlay: Option<&Overlay>,
+) -> Result<Path, StartError> {
+  let mut mapping = source.load()?;
+  for (name, mapping_file) in attached {
+    // Left out like a broken record, failing here would fail every reload
+    // until it is detached
+    match read_mapping_file(mapping_file) {
+      Ok(attachment) => mapping.attach(name.clone(), attachment),
+      Err(err) => error!(LOG, "Skipping attached {} from {}: {}", name, mapping_file, err),
+    }
+  }
+  mapping.resolve_blobs(blob_root).map_err(StartError::Mapping)?;
+  // Archives become plain folders first, so the upper layer can change
+  // entries inside them like anywhere else
+  archive::expand(&mut mapping);
+  if let Some(overlay) = overlay {
+    overlay.apply(&mut mapping).map_err(StartError::Io)?;
+  }
+  Ok(mapping)
+}
+
+/// Re-reads the mapping with the attached subtrees changed by `edit` and
+/// swaps in a new inode table. The mapping is loaded on the blocking pool
+/// without holding `inner`, only the swap waits for the lock. Open file
+/// handles are untouched, so readers keep going across a reload, cached blob
+/// metadata is dropped.
+pub(crate) async fn reload<F>(inner: &RwLock<Inner>, edit: F) -> Result<usize, String>
+where
+  F: FnOnce(&mut BTreeMap<String, String>) -> Result<(), String>,
+{
+  let reloading = inner.read().await.reloading.clone();
+  // Two reloads at once would each swap in a table missing the other's edit
+  let _reloading = reloading.lock().await;
+  let (source, mut attached, blob_root, overlay) = {
+    let inner = inner.read().await;
+    (inner.source.clone(), inner.attached.clone(), inner.blob_root.clone(), inner.overlay.clone())
+  };
+  edit(&mut attached)?;
+
+  let loading = attached.clone();
+  let mapping = tokio::task::spawn_blocking(move || load(&source, &loading, blob_root.as_deref(), overlay.as_ref()))
+    .await
+    .map_err(|err| err.to_string())?
+    .map_err(|err| err.to_string())?;
+  let root = Arc::new(SyncRwLock::new(mapping.into()));
+
+  let mut inner = inner.write().await;
+  let previous: HashMap<u64, String> = inner.inode_table.files().into_iter().collect();
+  inner.inode_table = inner.inode_table.rebuild(root);
+  let stale = changed_files(&previous, inner.inode_table.files());
+  inner.stale.extend(stale);
+  inner.attached = attached;
+  inner.metadata.clear();
+  Ok(inner.inode_table.len())
+}
+
+/// Inode numbers of `files` that point at another blob than they did in
+/// `previous`.
+fn changed_files(previous: &HashMap<u64, String>, files: Vec<(u64, String)>) -> Vec<u64> {
+  files
+    .into_iter()
+    .filter(|(ino, target)| previous.get(ino).is_some_and(|old| old != target))
+    .map(|(ino, _)| ino)
+    .collect()
+}
+
+/// Reloads the mapping on SIGHUP, and on every change of its mtime when
+/// `interval` is set.
+pub(crate) async fn watch(inner: Arc<RwLock<Inner>>, source: MappingSource, interval: Option<Duration>) {
+  let mut hangup = match signal(SignalKind::hangup()) {
+    Ok(hangup) => hangup,
+    Err(err) => {
+      error!(LOG, "Failed to listen for SIGHUP: {}", err);
+      return;
+    }
+  };
+
+  let mut last_modified = source.modified().await;
+  loop {
+    tokio::select! {
+      _ = hangup.recv() => {
+        info!(LOG, "Received SIGHUP, reloading {}", source);
+      }
+      _ = tick(interval) => {
+        let current = source.modified().await;
+        if current == last_modified {
+          continue;
+        }
+        last_modified = current;
+        info!(LOG, "Mapping {} changed, reloading", source);
+      }
+    }
+
+    match reload(&inner, |_| Ok(())).await {
+      Ok(count) => info!(LOG, "Reloaded {} with {} inodes", source, count),
+      Err(err) => error!(LOG, "Failed to reload mapping {}: {}", source, err),
+    }
+  }
+}
+
+async fn tick(interval: Option<Duration>) {
+  match interval {
+    Some(interval) => tokio::time::sleep(interval).await,
+    None => std::future::pending().await,
+  }
+}
+
+async fn modified(path: &str) -> Option<SystemTime> {
+  tokio::fs::metadata(path).await.and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_load_dir() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    std::fs::copy("res/mapping-tree.json", dir.join("record1.json")).unwrap();
+    std::fs::copy("res/mapping-tree.staging.json", dir.join("record2.json")).unwrap();
+    std::fs::write(dir.join("README"), "not a mapping").unwrap();
+    std::fs::write(dir.join("record3.json"), "{ not json").unwrap();
+
+    let source = MappingSource::Dir(dir.to_string_lossy().into_owned());
+    let loaded = source.load();
+
+    let Ok(Path::Folder { paths, .. }) = loaded else {
+      panic!("mapping dir should load into a folder");
+    };
+    let mut records: Vec<&String> = paths.keys().collect();
+    records.sort();
+    assert_eq!(records, vec!["record1", "record2"]);
+  }
+
+  #[test]
+  fn test_load_attached() {
+    let source = MappingSource::File(String::from("res/mapping-tree.json"));
+    let attached = BTreeMap::from([
+      (String::from("staging"), String::from("res/mapping-tree.staging.json")),
+      (String::from("broken"), String::from("res/missing.json")),
+    ]);
+    let Ok(Path::Folder { paths, .. }) = load(&source, &attached, None, None) else {
+      panic!("mapping should load without the broken attachment");
+    };
+    assert!(paths.contains_key("staging"));
+    assert!(!paths.contains_key("broken"));
+  }
+
+  #[test]
+  fn test_changed_files() {
+    let previous = HashMap::from([(2, "/blobs/a".to_string()), (3, "/blobs/b".to_string())]);
+    let files = vec![(2, "/blobs/a".to_string()), (3, "/blobs/c".to_string()), (4, "/blobs/d".to_string())];
+    assert_eq!(changed_files(&previous, files), vec![3]);
+  }
+}
@@ -0,0 +1,160 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use fuser::BackgroundSession;
+use slog::{error, info, warn};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex as AsyncMutex, Notify, RwLock};
+use crate::{FileHandle, Inner, LOG};
+
+/// Why the filesystem is going down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reason {
+  Signal(&'static str),
+  /// Asked for through the control socket.
+  Requested,
+  /// Unmounted from outside, e.g. with `fusermount -u`.
+  Unmounted,
+}
+
+impl Display for Reason {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Reason::Signal(name) => write!(f, "received {}", name),
+      Reason::Requested => write!(f, "unmount requested through the control socket"),
+      Reason::Unmounted => write!(f, "filesystem was unmounted"),
+    }
+  }
+}
+
+/// Wakes `wait` once anything asks the filesystem to go down.
+pub(crate) struct Shutdown {
+  reason: Mutex<Option<Reason>>,
+  notify: Notify,
+  /// SIGTERM and SIGINT, listened for from the start so that one arriving
+  /// during startup is served rather than killing the process.
+  signals: AsyncMutex<(Option<Signal>, Option<Signal>)>,
+}
+
+impl Shutdown {
+  /// Starts listening for SIGTERM and SIGINT on `runtime`.
+  pub(crate) fn new(runtime: &Handle) -> Self {
+    let _guard = runtime.enter();
+    Shutdown {
+      reason: Mutex::new(None),
+      notify: Notify::new(),
+      signals: AsyncMutex::new((
+        listen(SignalKind::terminate(), "SIGTERM"),
+        listen(SignalKind::interrupt(), "SIGINT"),
+      )),
+    }
+  }
+
+  /// The first reason given wins, later requests are already being served.
+  pub(crate) fn request(&self, reason: Reason) {
+    let mut current = self.reason.lock().unwrap();
+    if current.is_none() {
+      *current = Some(reason);
+      self.notify.notify_one();
+    }
+  }
+
+  /// Resolves on SIGTERM, SIGINT or the first `request`.
+  pub(crate) async fn wait(&self) -> Reason {
+    let mut signals = self.signals.lock().await;
+    let (terminate, interrupt) = &mut *signals;
+    loop {
+      tokio::select! {
+        _ = recv(terminate) => self.request(Reason::Signal("SIGTERM")),
+        _ = recv(interrupt) => self.request(Reason::Signal("SIGINT")),
+        _ = self.notify.notified() => {}
+      }
+      if let Some(reason) = *self.reason.lock().unwrap() {
+        return reason;
+      }
+    }
+  }
+}
+
+fn listen(kind: SignalKind, name: &str) -> Option<Signal> {
+  match signal(kind) {
+    Ok(signal) => Some(signal),
+    Err(err) => {
+      error!(LOG, "Failed to listen for {}: {}", name, err);
+      None
+    }
+  }
+}
+
+async fn recv(signal: &mut Option<Signal>) {
+  match signal {
+    Some(signal) => {
+      signal.recv().await;
+    }
+    None => std::future::pending().await,
+  }
+}
+
+/// Refuses new opens, gives reads already running up to `timeout` to finish
+/// and closes every file handle. Returns whether all reads finished in time.
+pub(crate) async fn drain(inner: &RwLock<Inner>, timeout: Duration) -> bool {
+  let (handles, access_log) = {
+    let mut inner = inner.write().await;
+    inner.closing = true;
+    (std::mem::take(&mut inner.file_handles), inner.access_log.clone())
+  };
+  info!(LOG, "Closing {} file handles", handles.len());
+
+  // A read or write in flight holds its own reference to the handle
+  let busy = |handles: &[(u64, Arc<FileHandle>)]| handles.iter().filter(|(_, handle)| Arc::strong_count(handle) > 1).count();
+  let handles: Vec<(u64, Arc<FileHandle>)> = handles.into_iter().collect();
+  let drained = tokio::time::timeout(timeout, async {
+    while busy(&handles) > 0 {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .is_ok();
+  if !drained {
+    warn!(LOG, "Gave up waiting for {} file handles still in use", busy(&handles));
+  }
+
+  if let Some(access_log) = access_log {
+    for (fh, handle) in &handles {
+      access_log.released(*fh, handle.ino, &handle.target, &handle.access);
+    }
+    // The writer thread dies with the process, everything logged must be out
+    let _ = tokio::task::spawn_blocking(move || access_log.flush()).await;
+  }
+  drained
+}
+
+/// Unmounts the filesystem and hands back the session thread, which ends
+/// once the kernel let go of the mount.
+pub(crate) fn unmount(session: BackgroundSession) -> JoinHandle<std::io::Result<()>> {
+  info!(LOG, "Unmounting {}", session.mountpoint.display());
+  let guard;
+  {
+    let session = session;
+    guard = session.guard;
+    // This is the unmount: what is left of `session` is the mount, a field
+    // fuser keeps private so it cannot be dropped by name, and it goes with
+    // the end of this block
+  }
+  guard
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_request() {
+    let shutdown = Shutdown::new(&Handle::current());
+    shutdown.request(Reason::Requested);
+    shutdown.request(Reason::Unmounted);
+    assert_eq!(shutdown.wait().await, Reason::Requested);
+  }
+}
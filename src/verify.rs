@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use sha2::{Digest, Sha256};
+use slog::error;
+use crate::{read_full_at, LOG};
+
+/// Blobs remembered as intact at most, the ones checked longest ago go first.
+const VERIFIED_CAPACITY: usize = 4096;
+/// Bytes hashed at a time.
+const CHUNK: usize = 1 << 20;
+
+/// Size and mtime of a blob.
+type Stamp = (u64, SystemTime);
+
+/// Checks blobs against their sha256 before they are served, so bit-rot or
+/// tampered storage fails the open instead of reaching readers.
+pub(crate) struct Verifier {
+  /// How long a blob found intact is trusted without hashing it again.
+  ttl: Duration,
+  /// Blobs found intact, with the size and mtime they had then and when they
+  /// were hashed. A blob is hashed again once its entry expired, as rot does
+  /// not touch the mtime.
+  verified: Mutex<HashMap<String, (Stamp, Instant)>>,
+}
+
+impl Verifier {
+  pub(crate) fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      verified: Default::default(),
+    }
+  }
+
+  /// Hashes the open `file` of `target` and compares it to `sha256`, or to the
+  /// blob's file name when that is a digest. Blobs with neither pass
+  /// unchecked.
+  pub(crate) async fn verify(&self, file: &File, target: &str, sha256: Option<&str>) -> Result<(), Error> {
+    let Some(expected) = expected_digest(target, sha256) else {
+      return Ok(());
+    };
+    let metadata = file.metadata()?;
+    let stamp = (metadata.len(), metadata.modified()?);
+    if let Some((verified, at)) = self.verified.lock().unwrap().get(target) {
+      if *verified == stamp && at.elapsed() < self.ttl {
+        return Ok(());
+      }
+    }
+
+    let file = file.try_clone()?;
+    let actual = tokio::task::spawn_blocking(move || digest(&file)).await??;
+    if actual != expected {
+      error!(LOG, "Blob {} does not match its sha256: expected {}, got {}", target, expected, actual);
+      self.verified.lock().unwrap().remove(target);
+      return Err(Error::new(ErrorKind::InvalidData, "sha256 mismatch"));
+    }
+    self.remember(target, stamp);
+    Ok(())
+  }
+
+  fn remember(&self, target: &str, stamp: Stamp) {
+    if self.ttl.is_zero() {
+      return;
+    }
+    let mut verified = self.verified.lock().unwrap();
+    if verified.len() >= VERIFIED_CAPACITY {
+      verified.retain(|_, (_, at)| at.elapsed() < self.ttl);
+    }
+    if verified.len() >= VERIFIED_CAPACITY {
+      let oldest = verified.iter().min_by_key(|(_, (_, at))| *at).map(|(target, _)| target.clone());
+      if let Some(oldest) = oldest {
+        verified.remove(&oldest);
+      }
+    }
+    verified.insert(target.to_string(), (stamp, Instant::now()));
+  }
+}
+
+pub(crate) fn expected_digest(target: &str, sha256: Option<&str>) -> Option<String> {
+  let expected = match sha256 {
+    Some(sha256) => sha256,
+    None => std::path::Path::new(target).file_name()?.to_str()?,
+  };
+  let is_digest = expected.len() == 64 && expected.bytes().all(|byte| byte.is_ascii_hexdigit());
+  is_digest.then(|| expected.to_ascii_lowercase())
+}
+
+/// Positional reads leave the offset of `file` alone, it is shared with the
+/// handle being opened.
+fn digest(file: &File) -> Result<String, Error> {
+  let mut hasher = Sha256::new();
+  let mut offset = 0;
+  loop {
+    let chunk = read_full_at(file, offset, CHUNK)?;
+    if chunk.is_empty() {
+      break;
+    }
+    hasher.update(&chunk);
+    offset += chunk.len() as u64;
+  }
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use fuser::TimeOrNow;
+
+  #[tokio::test]
+  async fn test_verify() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    // sha256 of "hello"
+    let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    let good = dir.join(sha256);
+    std::fs::write(&good, b"hello").unwrap();
+    let bad = dir.join(sha256.replace('2', "3"));
+    std::fs::write(&bad, b"hello").unwrap();
+    let plain = dir.join("hello.txt");
+    std::fs::write(&plain, b"tampered").unwrap();
+
+    async fn verify(verifier: &Verifier, path: &std::path::Path, sha256: Option<&str>) -> Result<(), Error> {
+      verifier.verify(&File::open(path)?, &path.to_string_lossy(), sha256).await
+    }
+    let verifier = Verifier::new(Duration::from_secs(60));
+    verify(&verifier, &good, None).await.unwrap();
+    let err = verify(&verifier, &bad, None).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    verify(&verifier, &plain, None).await.unwrap();
+    assert!(verify(&verifier, &plain, Some(sha256)).await.is_err());
+    verify(&verifier, &bad, Some(&sha256.to_uppercase())).await.unwrap();
+    assert_eq!(verifier.verified.lock().unwrap().len(), 2);
+
+    // Rot keeps size and mtime, only an expired entry notices it
+    let mtime = std::fs::metadata(&good).unwrap().modified().unwrap();
+    std::fs::write(&good, b"jello").unwrap();
+    crate::set_times(&good.to_string_lossy(), None, Some(TimeOrNow::SpecificTime(mtime))).unwrap();
+    verify(&verifier, &good, None).await.unwrap();
+    let verifier = Verifier::new(Duration::ZERO);
+    assert!(verify(&verifier, &good, None).await.is_err());
+    assert!(verifier.verified.lock().unwrap().is_empty());
+  }
+}
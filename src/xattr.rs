@@ -0,0 +1,122 @@
+use std::path::Path;
+use crate::inode::INode;
+use crate::verify::expected_digest;
+
+pub(crate) const TARGET: &str = "user.fsproxy.target";
+pub(crate) const ARCHIVE: &str = "user.fsproxy.archive";
+pub(crate) const SHA256: &str = "user.fsproxy.sha256";
+pub(crate) const PROJECT: &str = "user.fsproxy.project";
+pub(crate) const RECORD: &str = "user.fsproxy.record";
+
+/// Extended attributes tracing a file back to its blob. Blob stores lay their
+/// blobs out as `.../projects/<project>/records/<record>/blobs/<sha256>`, the
+/// ids are taken from there when the target follows that layout. A member of
+/// an archive names the blob holding the archive instead, the digest of that
+/// is not the member's. Folders and symlinks have none.
+pub(crate) fn attributes(inode: &INode) -> Vec<(&'static str, String)> {
+  let INode::File { target, member, sha256, .. } = inode else {
+    return vec![];
+  };
+  let mut attributes = vec![];
+  if member.is_some() {
+    attributes.push((ARCHIVE, target.clone()));
+  } else {
+    attributes.push((TARGET, target.clone()));
+    if let Some(sha256) = expected_digest(target, sha256.as_deref()) {
+      attributes.push((SHA256, sha256));
+    }
+  }
+  if let Some(project) = component_after(target, "projects") {
+    attributes.push((PROJECT, project));
+  }
+  if let Some(record) = component_after(target, "records") {
+    attributes.push((RECORD, record));
+  }
+  attributes
+}
+
+/// Value of `name`, if `inode` has it.
+pub(crate) fn get(inode: &INode, name: &str) -> Option<String> {
+  attributes(inode).into_iter().find(|(key, _)| *key == name).map(|(_, value)| value)
+}
+
+/// The names of all attributes of `inode` in the NUL separated form of
+/// listxattr(2).
+pub(crate) fn list(inode: &INode) -> Vec<u8> {
+  let mut names = vec![];
+  for (name, _) in attributes(inode) {
+    names.extend_from_slice(name.as_bytes());
+    names.push(0);
+  }
+  names
+}
+
+/// The path component right after the last `marker` directory of `target`.
+/// The blob itself is never taken, a component needs another one after it.
+fn component_after(target: &str, marker: &str) -> Option<String> {
+  let components: Vec<&str> = Path::new(target)
+    .iter()
+    .filter_map(|component| component.to_str())
+    .collect();
+  let position = components.iter().rposition(|component| *component == marker)?;
+  if position + 2 >= components.len() {
+    return None;
+  }
+  Some(components[position + 1].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive::{ArchiveMember, Compression};
+  use crate::mapping::Metadata;
+
+  fn file(target: &str, sha256: Option<&str>) -> INode {
+    INode::File {
+      ino: 2,
+      parent: 1,
+      name: String::from("bundle.zip"),
+      target: target.to_string(),
+      member: None,
+      sha256: sha256.map(String::from),
+      meta: Metadata::default(),
+    }
+  }
+
+  #[test]
+  fn test_attributes() {
+    let sha256 = "1bf85cea492634c087e57a920f8b20247a434614e7353555627935e2e051e19f";
+    let target = format!(
+      "/default/projects/fd26ca5a-063c-4f47-8c24-cd620a385f18/records/f85a59a3-a3ac-4c89-b10a-75b19065d7ab/blobs/{}",
+      sha256,
+    );
+    let mut blob = file(&target, None);
+    assert_eq!(get(&blob, TARGET).unwrap(), target);
+    assert_eq!(get(&blob, SHA256).unwrap(), sha256);
+    assert_eq!(get(&blob, PROJECT).unwrap(), "fd26ca5a-063c-4f47-8c24-cd620a385f18");
+    assert_eq!(get(&blob, RECORD).unwrap(), "f85a59a3-a3ac-4c89-b10a-75b19065d7ab");
+    assert!(get(&blob, "user.other").is_none());
+    assert_eq!(
+      list(&blob),
+      b"user.fsproxy.target\0user.fsproxy.sha256\0user.fsproxy.project\0user.fsproxy.record\0",
+    );
+
+    let plain = file("/srv/upper/records/notes.txt", Some(&sha256.to_uppercase()));
+    assert_eq!(get(&plain, SHA256).unwrap(), sha256);
+    assert!(get(&plain, RECORD).is_none());
+    assert!(get(&plain, PROJECT).is_none());
+
+    let INode::File { member, .. } = &mut blob else {
+      unreachable!("blob is a file");
+    };
+    *member = Some(ArchiveMember { data_start: 512, compressed_size: 5, size: 5, compression: Compression::Stored });
+    assert_eq!(get(&blob, ARCHIVE).unwrap(), target);
+    assert!(get(&blob, TARGET).is_none());
+    assert!(get(&blob, SHA256).is_none());
+    assert_eq!(get(&blob, RECORD).unwrap(), "f85a59a3-a3ac-4c89-b10a-75b19065d7ab");
+    assert_eq!(list(&blob), b"user.fsproxy.archive\0user.fsproxy.project\0user.fsproxy.record\0");
+
+    let symlink = INode::Symlink { ino: 3, parent: 1, name: String::from("link"), target };
+    assert!(list(&symlink).is_empty());
+  }
+}